@@ -1,3 +1,5 @@
+#![allow(unused_imports)]
+
 use std::env::current_dir;
 use std::fs::create_dir_all;
 
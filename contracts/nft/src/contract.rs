@@ -1,14 +1,40 @@
+use std::collections::BTreeMap;
+
 use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
-use cosmwasm_std::{Empty, Coin, Uint128};
+use cosmwasm_std::{to_binary, Addr, BankMsg, Coin, CosmosMsg, DistributionMsg, Empty, StakingMsg, StdResult, Timestamp, Uint128, WasmMsg};
 use cw2::set_contract_version;
-pub use cw721_base::{Cw721Contract, ContractError, InstantiateMsg, QueryMsg, ExecuteMsg, MintMsg, MinterResponse};
+use cw20::Cw20ExecuteMsg;
+use cw721::Expiration;
+use cw_storage_plus::Bound;
+pub use cw721_base::{Cw721Contract, QueryMsg, ExecuteMsg, MintMsg, MinterResponse};
+
+pub use crate::error::ContractError;
+pub use crate::msg::InstantiateMsg;
+use crate::state::{
+    accrue_delegator_rewards, add_delegator_rewards, add_to_totals, auto_compounds, checkpoint_rewards, claim_rewards,
+    close_delegator_rewards, close_rewards, credit_rewards, fund_rewards, load_stake, pending_delegator_rewards, slash_checkpoint, slash_index, take_delegator_rewards, pending_rewards, push_unbonding, release_rewards, released_pools, reward_liabilities, save_stake,
+    snapshot_token, sub_from_totals, total_principal, unbonding_total, update_power, Config, DepositPolicy, EarlyExit, MetadataPolicy,
+    PenaltySink, Redemption, TokenStake, TransferPolicy, Unbonding, UnbondingBatch, AUTO_COMPOUND, BATCH_TOKENS, CONFIG, TOKEN_STAKES,
+    TOTAL_CW20, UNBONDING_BATCH, LAST_HARVEST, SLASH_CHECKPOINTS, SLASH_INDEX,
+    OWNER_TOKENS, TOKEN_BALANCES, TOKEN_OWNERS, TOTAL_NATIVE, TOTAL_POWER, VALIDATOR_DELEGATIONS, VESTING_WITHDRAWN,
+    VOTING_POWER,
+};
 
 // Version info for migration
 const CONTRACT_NAME: &str = "crates.io:cw721-angel";
 const CONTRACT_VERSION: &str = env!("CARGO_PKG_VERSION");
 
+// Usual unbonding time of Cosmos SDK chains, 21 days
+const DEFAULT_UNBONDING_PERIOD: u64 = 21 * 24 * 60 * 60;
+
+// Most tokens a single split can mint
+const MAX_SPLIT_PARTS: usize = 10;
+// Tokens a single compound pays by default, and at most
+const DEFAULT_COMPOUND_LIMIT: u32 = 30;
+const MAX_COMPOUND_LIMIT: u32 = 100;
+
 
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug, Default)]
 pub struct Cw20 {
@@ -16,26 +42,192 @@ pub struct Cw20 {
     pub amount: Uint128,
 }
 
+/// The token extension doubles as its escrow ledger: the native coins and cw20 tokens
+/// listed here are held by the contract on behalf of the token and are released when
+/// it is burned.
 #[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug, Default)]
 pub struct Metadata {
     pub native: Option<Vec<Coin>>,    
     pub cw20: Option<Vec<Cw20>>,
+    /// The escrow can't be redeemed or withdrawn before then, no lock if unset
+    pub locked_until: Option<Expiration>,
+    /// Schedule the whole escrow vests on, only vested assets can leave the token
+    pub vesting: Option<Vesting>,
+}
+
+/// Linear vesting between `start` and `end`, nothing vests before the `cliff`
+#[derive(Serialize, Deserialize, Clone, PartialEq, JsonSchema, Debug)]
+pub struct Vesting {
+    pub start: Timestamp,
+    pub cliff: Timestamp,
+    pub end: Timestamp,
+}
+
+impl Vesting {
+    /// Part of `amount` vested at `now`
+    pub fn vested(&self, amount: Uint128, now: Timestamp) -> Uint128 {
+        if now < self.cliff {
+            Uint128::zero()
+        } else if now >= self.end {
+            amount
+        } else {
+            amount.multiply_ratio(now.nanos() - self.start.nanos(), self.end.nanos() - self.start.nanos())
+        }
+    }
+}
+
+impl Metadata {
+    /// Native coins escrowed for the token, sorted by denom with duplicates merged
+    pub fn native_balance(&self) -> Vec<Coin> {
+        normalize_coins(self.native.clone().unwrap_or_default())
+    }
+
+    /// Cw20 tokens escrowed for the token, sorted by contract with duplicates merged
+    pub fn cw20_balance(&self) -> Vec<Cw20> {
+        normalize_cw20(self.cw20.clone().unwrap_or_default())
+    }
+
+    /// Replaces the native escrow, storing `None` once nothing is left
+    pub fn set_native(&mut self, coins: Vec<Coin>) {
+        let coins = normalize_coins(coins);
+        self.native = if coins.is_empty() { None } else { Some(coins) };
+    }
+
+    /// Replaces the cw20 escrow, storing `None` once nothing is left
+    pub fn set_cw20(&mut self, cw20: Vec<Cw20>) {
+        let cw20 = normalize_cw20(cw20);
+        self.cw20 = if cw20.is_empty() { None } else { Some(cw20) };
+    }
+
+    /// Adds `amount` of the cw20 at `contract_address` to the escrow
+    pub fn add_cw20(&mut self, contract_address: &str, amount: Uint128) {
+        let mut cw20 = self.cw20_balance();
+        cw20.push(Cw20 { contract_address: contract_address.to_string(), amount });
+        self.set_cw20(cw20);
+    }
+
+    /// Adds every asset escrowed in `other` to this escrow, keeping the later lock-up.
+    /// Lock-ups at a height and at a time don't compare, this escrow keeps its own then
+    pub fn merge(&mut self, other: &Metadata) {
+        if other.locked_until > self.locked_until {
+            self.locked_until = other.locked_until;
+        }
+
+        let mut native = self.native_balance();
+        native.extend(other.native_balance());
+        self.set_native(native);
+
+        let mut cw20 = self.cw20_balance();
+        cw20.extend(other.cw20_balance());
+        self.set_cw20(cw20);
+    }
+
+    /// Whether both escrows hold exactly the same assets
+    pub fn same_balance(&self, other: &Metadata) -> bool {
+        self.native_balance() == other.native_balance() && self.cw20_balance() == other.cw20_balance()
+    }
+
+    /// Takes `coins` out of the native escrow, failing if the token holds less
+    /// than requested of any denom
+    pub fn sub_native(&mut self, coins: Vec<Coin>) -> Result<(), ContractError> {
+        let mut balance: BTreeMap<String, Uint128> = self
+            .native_balance()
+            .into_iter()
+            .map(|coin| (coin.denom, coin.amount))
+            .collect();
+        for coin in normalize_coins(coins) {
+            checked_sub_entry(&mut balance, coin.denom, coin.amount)?;
+        }
+        self.set_native(balance.into_iter().map(|(denom, amount)| Coin { denom, amount }).collect());
+        Ok(())
+    }
+
+    /// Takes `cw20` out of the cw20 escrow, failing if the token holds less
+    /// than requested of any contract
+    pub fn sub_cw20(&mut self, cw20: Vec<Cw20>) -> Result<(), ContractError> {
+        let mut balance: BTreeMap<String, Uint128> = self
+            .cw20_balance()
+            .into_iter()
+            .map(|token| (token.contract_address, token.amount))
+            .collect();
+        for token in normalize_cw20(cw20) {
+            checked_sub_entry(&mut balance, token.contract_address, token.amount)?;
+        }
+        self.set_cw20(balance.into_iter().map(|(contract_address, amount)| Cw20 { contract_address, amount }).collect());
+        Ok(())
+    }
+
+    /// Messages paying every escrowed asset out to `recipient`
+    pub fn payout_msgs(&self, recipient: &str) -> StdResult<Vec<CosmosMsg>> {
+        let mut msgs = vec![];
+        let native = self.native_balance();
+        if !native.is_empty() {
+            msgs.push(BankMsg::Send { to_address: recipient.to_string(), amount: native }.into());
+        }
+        for cw20 in self.cw20_balance() {
+            msgs.push(WasmMsg::Execute {
+                contract_addr: cw20.contract_address,
+                msg: to_binary(&Cw20ExecuteMsg::Transfer {
+                    recipient: recipient.to_string(),
+                    amount: cw20.amount,
+                })?,
+                funds: vec![],
+            }.into());
+        }
+        Ok(msgs)
+    }
+}
+
+fn checked_sub_entry(
+    balance: &mut BTreeMap<String, Uint128>,
+    asset: String,
+    requested: Uint128,
+) -> Result<(), ContractError> {
+    let available = balance.get(&asset).copied().unwrap_or_default();
+    if available < requested {
+        return Err(ContractError::Overdraft { asset, available, requested });
+    }
+    balance.insert(asset, available - requested);
+    Ok(())
+}
+
+/// Sorts coins by denom, merging duplicate denoms and dropping zero amounts,
+/// so two lists describing the same funds compare equal.
+pub fn normalize_coins(coins: Vec<Coin>) -> Vec<Coin> {
+    let mut merged: BTreeMap<String, Uint128> = BTreeMap::new();
+    for coin in coins {
+        *merged.entry(coin.denom).or_default() += coin.amount;
+    }
+    merged
+        .into_iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .map(|(denom, amount)| Coin { denom, amount })
+        .collect()
 }
 
-// impl Metadata {
-//     pub fn has_native_or_cw20(&self) -> bool {
-//         self.native.is_some() || self.cw20.is_some()
-//     }
-// }
+/// Same as `normalize_coins` for cw20 balances, keyed by contract address
+pub fn normalize_cw20(cw20: Vec<Cw20>) -> Vec<Cw20> {
+    let mut merged: BTreeMap<String, Uint128> = BTreeMap::new();
+    for token in cw20 {
+        *merged.entry(token.contract_address).or_default() += token.amount;
+    }
+    merged
+        .into_iter()
+        .filter(|(_, amount)| !amount.is_zero())
+        .map(|(contract_address, amount)| Cw20 { contract_address, amount })
+        .collect()
+}
 
 pub type Extension = Metadata;      
                                    
 pub mod entry {
-    use crate::msg::{ExecuteMsg, QueryMsg};
+    use crate::msg::{CollectionInfoResponse, ConfigUpdate, DelegationResponse, ExecuteMsg, OwnerOfAtHeightResponse, OwnsTokenAtHeightResponse, PendingRewardsResponse, QueryMsg, ReceiveMsg, SolvencyResponse, TokenBalanceAtHeightResponse, TokenBalanceResponse, TotalPowerAtHeightResponse, VestedResponse, VotingPowerAtHeightResponse};
 
     use super::*;
 
-    use cosmwasm_std::{Binary, Deps, DepsMut, Env, MessageInfo, Response, StdResult, entry_point};
+    use cosmwasm_std::{from_binary, Binary, Decimal, Deps, DepsMut, Env, MessageInfo, Event, Order, Response, StdError, Storage, entry_point};
+    use cw20::{BalanceResponse, Cw20QueryMsg, Cw20ReceiveMsg};
+    use cw721_base::state::TokenInfo;
 
     #[cfg_attr(not(feature = "library"), entry_point)]
     pub fn instantiate(
@@ -45,7 +237,40 @@ pub mod entry {
         msg: InstantiateMsg,
     ) -> Result<Response, ContractError> {
         let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
-        let res = cw721_base::Cw721Contract::instantiate(&contract, deps.branch(), env, info, msg)?;
+        let config = Config {
+            deposit_policy: msg.deposit_policy.unwrap_or_default(),
+            metadata_policy: msg.metadata_policy.unwrap_or_default(),
+            staking_denom: msg.staking_denom,
+            reward_denoms: msg.reward_denoms.unwrap_or_default(),
+            accepted_cw20s: msg.accepted_cw20s.unwrap_or_default(),
+            validators: msg.validators.unwrap_or_default(),
+            unbonding_period: msg.unbonding_period.unwrap_or(DEFAULT_UNBONDING_PERIOD),
+            unbonding_transfers: msg.unbonding_transfers.unwrap_or_default(),
+            locked_transfers: msg.locked_transfers.unwrap_or(TransferPolicy::Allowed),
+            early_exit: msg.early_exit,
+            voting_weights: msg.voting_weights.unwrap_or_default(),
+            auto_compound: msg.auto_compound.unwrap_or_default(),
+        };
+        validate_validators(deps.as_ref(), &config.validators)?;
+        for contract_address in &config.accepted_cw20s {
+            deps.api.addr_validate(contract_address)?;
+        }
+        for (i, weight) in config.voting_weights.iter().enumerate() {
+            if config.voting_weights[..i].iter().any(|other| other.asset == weight.asset) {
+                return Err(ContractError::DuplicateVotingWeight { asset: weight.asset.clone() });
+            }
+        }
+        if let Some(early_exit) = &config.early_exit {
+            validate_early_exit(deps.as_ref(), &config, early_exit)?;
+        }
+        CONFIG.save(deps.storage, &config)?;
+
+        let base_msg = cw721_base::InstantiateMsg {
+            name: msg.name,
+            symbol: msg.symbol,
+            minter: msg.minter,
+        };
+        let res = cw721_base::Cw721Contract::instantiate(&contract, deps.branch(), env, info, base_msg)?;
 
         // Explicitly set contract name and version, otherwise set to cw721-base info
         set_contract_version(deps.storage, CONTRACT_NAME, CONTRACT_VERSION)
@@ -57,19 +282,97 @@ pub mod entry {
 
     #[cfg_attr(not(feature = "library"), entry_point)]
     pub fn execute(
-        deps: DepsMut,
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        msg: ExecuteMsg<Metadata>,
+    ) -> Result<Response, ContractError> {
+        // Anything that moves escrowed assets must not leave the contract less able to pay
+        // every token than it was
+        let moves_funds = matches!(
+            msg,
+            ExecuteMsg::Mint(_)
+                | ExecuteMsg::Burn { .. }
+                | ExecuteMsg::Redeem { .. }
+                | ExecuteMsg::ExitEarly { .. }
+                | ExecuteMsg::ClaimUnbonded { .. }
+                | ExecuteMsg::UpdateMetadata { .. }
+                | ExecuteMsg::Deposit { .. }
+                | ExecuteMsg::Withdraw { .. }
+                | ExecuteMsg::Merge { .. }
+                | ExecuteMsg::Split { .. }
+                | ExecuteMsg::Receive(_)
+                | ExecuteMsg::DistributeRewards {}
+                | ExecuteMsg::FundRewards { .. }
+                | ExecuteMsg::ClaimRewards { .. }
+                | ExecuteMsg::Delegate { .. }
+                | ExecuteMsg::Undelegate { .. }
+                | ExecuteMsg::Unbond {}
+                | ExecuteMsg::Compound { .. }
+        );
+        // Reward streams pay out what is due to the principal staked until now
+        let total_principal = total_principal(deps.storage, &CONFIG.load(deps.storage)?)?;
+        release_rewards(deps.storage, env.block.time.seconds(), total_principal)?;
+        // Slashes lower the slash index, the tokens the message works on apply it first
+        let mut slashes: Vec<Event> = reconcile_delegations(deps.branch(), &env)?.into_iter().collect();
+        for token_id in staked_tokens(&msg) {
+            slashes.extend(settle_slash(deps.storage, &env, &token_id)?);
+        }
+        let owed_before = if moves_funds { liabilities(deps.storage)? } else { BTreeMap::new() };
+        let res = execute_msg(deps.branch(), env.clone(), info, msg)?.add_events(slashes);
+        if moves_funds {
+            assert_solvent(deps.as_ref(), &env, &owed_before, &res)?;
+        }
+        Ok(res)
+    }
+
+    fn execute_msg(
+        mut deps: DepsMut,
         env: Env,
         info: MessageInfo,
         msg: ExecuteMsg<Metadata>,
     ) -> Result<Response, ContractError> {
        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
        match msg {
+            ExecuteMsg::Mint(msg) => execute_mint(deps, env, info, msg),
+            ExecuteMsg::Burn { token_id } => execute_burn(deps, env, info, token_id),
+            ExecuteMsg::Redeem { token_id, recipient } => execute_redeem(deps, env, info, token_id, recipient),
+            ExecuteMsg::ExitEarly { token_id, recipient } => execute_exit_early(deps, env, info, token_id, recipient),
+            ExecuteMsg::ClaimUnbonded { token_id } => execute_claim_unbonded(deps, env, info, token_id),
             ExecuteMsg::UpdateMetadata {
                 token_id,
                 token_uri,
-                metadata,
-            } => execute_update_metadata(deps, env, info, token_id, token_uri, metadata),
-            _ => cw721_base::Cw721Contract::execute(&contract, deps, env, info, msg.into()),
+                native,
+                cw20,
+            } => execute_update_metadata(deps, env, info, token_id, token_uri, native, cw20),
+            ExecuteMsg::Deposit { token_id } => execute_deposit(deps, env, info, token_id),
+            ExecuteMsg::Withdraw { token_id, native, cw20 } => execute_withdraw(deps, env, info, token_id, native, cw20),
+            ExecuteMsg::Merge { token_ids, into } => execute_merge(deps, env, info, token_ids, into),
+            ExecuteMsg::Split { token_id, parts } => execute_split(deps, env, info, token_id, parts),
+            ExecuteMsg::Receive(msg) => execute_receive(deps, env, info, msg),
+            ExecuteMsg::DistributeRewards {} => execute_distribute_rewards(deps, env, info),
+            ExecuteMsg::FundRewards { denom, duration } => execute_fund_rewards(deps, env, info, denom, duration),
+            ExecuteMsg::ClaimRewards { token_ids } => execute_claim_rewards(deps, env, info, token_ids),
+            ExecuteMsg::Delegate { token_id } => execute_delegate(deps, env, info, token_id),
+            ExecuteMsg::Undelegate { token_id } => execute_undelegate(deps, env, info, token_id),
+            ExecuteMsg::Unbond {} => execute_unbond(deps, env, info),
+            ExecuteMsg::SetAutoCompound { token_id, auto_compound } => execute_set_auto_compound(deps, env, info, token_id, auto_compound),
+            ExecuteMsg::Compound { start_after, limit } => execute_compound(deps, env, info, start_after, limit),
+            ExecuteMsg::UpdateConfig(update) => execute_update_config(deps, env, info, update),
+            ExecuteMsg::TransferNft { ref token_id, .. } | ExecuteMsg::SendNft { ref token_id, .. } => {
+                assert_transferable(deps.as_ref(), &env, token_id)?;
+                let token_id = token_id.clone();
+                let old = load_token(deps.storage, &token_id)?;
+                let res = cw721_base::Cw721Contract::execute(&contract, deps.branch(), env.clone(), info, msg.try_into()?)?;
+                // Voting power and the ownership history follow the token
+                let new = load_token(deps.storage, &token_id)?;
+                let config = CONFIG.load(deps.storage)?;
+                update_power(deps.storage, env.block.height, &config, &old.owner, &old.extension, &Metadata::default())?;
+                update_power(deps.storage, env.block.height, &config, &new.owner, &Metadata::default(), &new.extension)?;
+                snapshot_token(deps.storage, env.block.height, &token_id, Some((&new.owner, &new.extension)))?;
+                Ok(res)
+            }
+            _ => Ok(cw721_base::Cw721Contract::execute(&contract, deps, env, info, msg.try_into()?)?),
         }
     }
 
@@ -80,133 +383,4012 @@ pub mod entry {
         msg: QueryMsg              
     ) -> StdResult<Binary> {
         let tract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
-        cw721_base::Cw721Contract::query(&tract, deps, env, msg.into())
+        match msg {
+            QueryMsg::CollectionInfo {} => return to_binary(&query_collection_info(deps)?),
+            QueryMsg::TokenBalance { token_id } => return to_binary(&query_token_balance(deps, env, token_id)?),
+            QueryMsg::Solvency {} => return to_binary(&query_solvency(deps, env)?),
+            QueryMsg::PendingRewards { token_id } => return to_binary(&query_pending_rewards(deps, env, token_id)?),
+            QueryMsg::Delegation { token_id } => return to_binary(&query_delegation(deps, env, token_id)?),
+            QueryMsg::Vested { token_id } => return to_binary(&query_vested(deps, env, token_id)?),
+            QueryMsg::VotingPowerAtHeight { address, height } => {
+                return to_binary(&query_voting_power_at_height(deps, env, address, height)?)
+            }
+            QueryMsg::TotalPowerAtHeight { height } => return to_binary(&query_total_power_at_height(deps, env, height)?),
+            QueryMsg::OwnerOfAtHeight { token_id, height } => {
+                return to_binary(&query_owner_of_at_height(deps, env, token_id, height)?)
+            }
+            QueryMsg::TokenBalanceAtHeight { token_id, height } => {
+                return to_binary(&query_token_balance_at_height(deps, env, token_id, height)?)
+            }
+            QueryMsg::OwnsTokenAtHeight { owner, token_id, height } => {
+                return to_binary(&query_owns_token_at_height(deps, env, owner, token_id, height)?)
+            }
+            _ => {}
+        }
+        let msg = msg.try_into().map_err(|err: ContractError| StdError::generic_err(err.to_string()))?;
+        cw721_base::Cw721Contract::query(&tract, deps, env, msg)
     }
 
-    fn execute_update_metadata(
-        deps: DepsMut,
-        _env: Env,
-        info: MessageInfo,
+    fn query_collection_info(deps: Deps) -> StdResult<CollectionInfoResponse> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let native = TOTAL_NATIVE
+            .range(deps.storage, None, None, Order::Ascending)
+            .map(|item| item.map(|(denom, amount)| Coin { denom, amount }))
+            .collect::<StdResult<_>>()?;
+        let cw20 = TOTAL_CW20
+            .range(deps.storage, None, None, Order::Ascending)
+            .map(|item| item.map(|(contract_address, amount)| Cw20 { contract_address, amount }))
+            .collect::<StdResult<_>>()?;
+        Ok(CollectionInfoResponse {
+            native,
+            cw20,
+            num_tokens: contract.token_count(deps.storage)?,
+            minter: contract.minter.load(deps.storage)?.to_string(),
+            config: CONFIG.load(deps.storage)?,
+        })
+    }
+
+    fn query_token_balance(deps: Deps, env: Env, token_id: String) -> StdResult<TokenBalanceResponse> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let metadata = contract.tokens.load(deps.storage, &token_id)?.extension;
+
+        // Tokens share the contract balance, so a token is only covered if the contract
+        // holds the whole collection's escrow of each of its assets plus the rewards owed in it
+        let rewards = reward_liabilities(deps.storage)?;
+        let mut backed = true;
+        for coin in metadata.native_balance() {
+            let total = TOTAL_NATIVE.may_load(deps.storage, &coin.denom)?.unwrap_or_default()
+                + native_amount(&rewards, &coin.denom);
+            backed &= native_held(deps, &env, &coin.denom)? >= total;
+        }
+        for cw20 in metadata.cw20_balance() {
+            let total = TOTAL_CW20.may_load(deps.storage, &cw20.contract_address)?.unwrap_or_default()
+                + cw20_amount(&rewards, &cw20.contract_address);
+            backed &= cw20_held(deps, &env, &cw20.contract_address)? >= total;
+        }
+        Ok(TokenBalanceResponse { metadata, backed })
+    }
+
+    fn query_solvency(deps: Deps, env: Env) -> StdResult<SolvencyResponse> {
+        // Sum the tokens themselves rather than trusting the running totals
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let mut escrowed = Metadata::default();
+        for item in contract.tokens.range(deps.storage, None, None, Order::Ascending) {
+            let (_, token) = item?;
+            escrowed.merge(&token.extension);
+        }
+
+        let rewards = reward_liabilities(deps.storage)?;
+        let mut owed = escrowed.clone();
+        owed.merge(&rewards);
+
+        let mut solvent = true;
+        let mut held_native = vec![];
+        for coin in owed.native_balance() {
+            let held = native_held(deps, &env, &coin.denom)?;
+            solvent &= held >= coin.amount;
+            held_native.push(Coin { denom: coin.denom, amount: held });
+        }
+        let mut held_cw20 = vec![];
+        for cw20 in owed.cw20_balance() {
+            let held = cw20_held(deps, &env, &cw20.contract_address)?;
+            solvent &= held >= cw20.amount;
+            held_cw20.push(Cw20 { contract_address: cw20.contract_address, amount: held });
+        }
+
+        Ok(SolvencyResponse {
+            solvent,
+            escrowed_native: escrowed.native_balance(),
+            escrowed_cw20: escrowed.cw20_balance(),
+            held_native,
+            held_cw20,
+            rewards_native: rewards.native_balance(),
+            rewards_cw20: rewards.cw20_balance(),
+        })
+    }
+
+    fn query_pending_rewards(deps: Deps, env: Env, token_id: String) -> StdResult<PendingRewardsResponse> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let token = contract.tokens.load(deps.storage, &token_id)?;
+        let config = CONFIG.load(deps.storage)?;
+        // Include what the streams released since the last execute
+        let pools = released_pools(deps.storage, env.block.time.seconds(), total_principal(deps.storage, &config)?)?;
+        let mut rewards = pending_rewards(deps.storage, &pools, &token_id, config.principal(&token.extension))?;
+        let bonded = load_stake(deps.storage, &token_id, &env.block)?.bonded();
+        rewards.merge(&delegator_metadata(pending_delegator_rewards(deps.storage, &token_id, bonded)?));
+        Ok(PendingRewardsResponse {
+            native: rewards.native_balance(),
+            cw20: rewards.cw20_balance(),
+        })
+    }
+
+    fn query_delegation(deps: Deps, env: Env, token_id: String) -> StdResult<DelegationResponse> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let token = contract.tokens.load(deps.storage, &token_id)?;
+        let mut stake = load_stake(deps.storage, &token_id, &env.block)?;
+        let loss = stake.slash(&slash_checkpoint(deps.storage, &token_id)?, &slash_index(deps.storage)?);
+        let config = CONFIG.load(deps.storage)?;
+        let principal = config.principal(&token.extension).saturating_sub(loss);
+        Ok(DelegationResponse {
+            delegated: stake.delegated,
+            liquid: principal.saturating_sub(stake.locked()),
+            unbonding: stake.unbonding,
+            redemption: stake.redemption,
+            auto_compound: auto_compounds(deps.storage, &config, &token_id)?,
+        })
+    }
+
+    fn query_voting_power_at_height(
+        deps: Deps,
+        env: Env,
+        address: String,
+        height: Option<u64>,
+    ) -> StdResult<VotingPowerAtHeightResponse> {
+        let address = deps.api.addr_validate(&address)?;
+        let height = height.unwrap_or(env.block.height);
+        let power = VOTING_POWER.may_load_at_height(deps.storage, &address, height)?.unwrap_or_default();
+        Ok(VotingPowerAtHeightResponse { power, height })
+    }
+
+    fn query_total_power_at_height(deps: Deps, env: Env, height: Option<u64>) -> StdResult<TotalPowerAtHeightResponse> {
+        let height = height.unwrap_or(env.block.height);
+        let power = TOTAL_POWER.may_load_at_height(deps.storage, height)?.unwrap_or_default();
+        Ok(TotalPowerAtHeightResponse { power, height })
+    }
+
+    fn query_owner_of_at_height(
+        deps: Deps,
+        env: Env,
         token_id: String,
-        token_uri: String,
-        metadata: Metadata
-    ) -> Result<Response, ContractError> {
+        height: Option<u64>,
+    ) -> StdResult<OwnerOfAtHeightResponse> {
+        let height = height.unwrap_or(env.block.height);
+        let owner = TOKEN_OWNERS.may_load_at_height(deps.storage, &token_id, height)?;
+        Ok(OwnerOfAtHeightResponse { owner: owner.map(String::from), height })
+    }
+
+    fn query_owns_token_at_height(
+        deps: Deps,
+        env: Env,
+        owner: String,
+        token_id: String,
+        height: Option<u64>,
+    ) -> StdResult<OwnsTokenAtHeightResponse> {
+        let owner = deps.api.addr_validate(&owner)?;
+        let height = height.unwrap_or(env.block.height);
+        let owns = OWNER_TOKENS.may_load_at_height(deps.storage, (&owner, &token_id), height)?.is_some();
+        Ok(OwnsTokenAtHeightResponse { owns, height })
+    }
+
+    fn query_token_balance_at_height(
+        deps: Deps,
+        env: Env,
+        token_id: String,
+        height: Option<u64>,
+    ) -> StdResult<TokenBalanceAtHeightResponse> {
+        let height = height.unwrap_or(env.block.height);
+        let escrow = TOKEN_BALANCES.may_load_at_height(deps.storage, &token_id, height)?.unwrap_or_default();
+        Ok(TokenBalanceAtHeightResponse {
+            native: escrow.native_balance(),
+            cw20: escrow.cw20_balance(),
+            height,
+        })
+    }
+
+    fn query_vested(deps: Deps, env: Env, token_id: String) -> StdResult<VestedResponse> {
         let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
-        let minter = contract.minter.load(deps.storage)?;
-        if info.sender != minter {
-            Err(ContractError::Unauthorized {})
-        } else {
-            contract
-                .tokens
-                .update(deps.storage, &token_id, |token| match token {
-                    Some(mut token_info) => {
-                        token_info.token_uri = Some(token_uri.clone());
-                        token_info.extension = metadata;
-                        Ok(token_info)
-                    },
-                    None => Err(ContractError::Unauthorized {}),
-                })?;
-            Ok(Response::new())
+        let escrow = contract.tokens.load(deps.storage, &token_id)?.extension;
+        let (vested, unvested) = vesting_status(deps.storage, &env, &token_id, &escrow)?;
+        let withdrawable_native = escrow
+            .native_balance()
+            .into_iter()
+            .map(|coin| Coin { amount: coin.amount.saturating_sub(native_amount(&unvested, &coin.denom)), denom: coin.denom })
+            .collect();
+        let withdrawable_cw20 = escrow
+            .cw20_balance()
+            .into_iter()
+            .map(|cw20| Cw20 {
+                amount: cw20.amount.saturating_sub(cw20_amount(&unvested, &cw20.contract_address)),
+                contract_address: cw20.contract_address,
+            })
+            .collect();
+        Ok(VestedResponse {
+            vested_native: vested.native_balance(),
+            vested_cw20: vested.cw20_balance(),
+            withdrawable_native: normalize_coins(withdrawable_native),
+            withdrawable_cw20: normalize_cw20(withdrawable_cw20),
+        })
+    }
+
+    /// What the contract owes in every asset, escrowed across all tokens plus the
+    /// rewards not paid out yet, with whether the asset is a cw20
+    fn liabilities(storage: &dyn Storage) -> StdResult<BTreeMap<String, (bool, Uint128)>> {
+        let mut owed = reward_liabilities(storage)?;
+        for item in TOTAL_NATIVE.range(storage, None, None, Order::Ascending) {
+            let (denom, amount) = item?;
+            owed.merge(&Metadata { native: Some(vec![Coin { denom, amount }]), cw20: None, locked_until: None, vesting: None });
+        }
+        for item in TOTAL_CW20.range(storage, None, None, Order::Ascending) {
+            let (contract_address, amount) = item?;
+            owed.add_cw20(&contract_address, amount);
         }
+        Ok(owed
+            .native_balance()
+            .into_iter()
+            .map(|coin| (coin.denom, (false, coin.amount)))
+            .chain(owed.cw20_balance().into_iter().map(|cw20| (cw20.contract_address, (true, cw20.amount))))
+            .collect())
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use super::*;
+    /// Fails with `Insolvent` if, once the messages in `res` ran, the contract is shorter
+    /// of an asset than it was with `owed_before`.
+    /// Only the assets the contract owes a different amount of, or sends out, are
+    /// queried. A contract that is already short, after a slash for instance, still
+    /// lets through whatever does not make it worse
+    fn assert_solvent(
+        deps: Deps,
+        env: &Env,
+        owed_before: &BTreeMap<String, (bool, Uint128)>,
+        res: &Response,
+    ) -> Result<(), ContractError> {
+        let (incoming, outgoing) = flows(deps, env, res.messages.iter().map(|sub| &sub.msg))?;
+        for (asset, (cw20, escrowed)) in liabilities(deps.storage)? {
+            let escrowed_before = owed_before.get(&asset).map(|(_, escrowed)| *escrowed).unwrap_or_default();
+            if escrowed == escrowed_before && !outgoing.contains_key(&asset) {
+                continue;
+            }
+            let balance = if cw20 { cw20_held(deps, env, &asset)? } else { native_held(deps, env, &asset)? };
+            let held = (balance + incoming.get(&asset).copied().unwrap_or_default())
+                .saturating_sub(outgoing.get(&asset).copied().unwrap_or_default());
+            if escrowed.saturating_sub(held) > escrowed_before.saturating_sub(balance) {
+                return Err(ContractError::Insolvent { asset, held, escrowed });
+            }
+        }
+        Ok(())
+    }
 
-    use cosmwasm_std::{testing::{mock_dependencies, mock_env, mock_info}, coins, from_binary};
-    use cw721::NftInfoResponse;
-    const CREATOR: &str = "creator";
+    /// Assets `msgs` bring into the contract and send out of it
+    fn flows<'a>(
+        deps: Deps,
+        env: &Env,
+        msgs: impl Iterator<Item = &'a CosmosMsg>,
+    ) -> StdResult<(BTreeMap<String, Uint128>, BTreeMap<String, Uint128>)> {
+        let mut incoming: BTreeMap<String, Uint128> = BTreeMap::new();
+        let mut outgoing: BTreeMap<String, Uint128> = BTreeMap::new();
+        for msg in msgs {
+            match msg {
+                CosmosMsg::Bank(BankMsg::Send { amount, .. }) => {
+                    for coin in amount {
+                        *outgoing.entry(coin.denom.clone()).or_default() += coin.amount;
+                    }
+                }
+                CosmosMsg::Wasm(WasmMsg::Execute { contract_addr, msg, .. }) => {
+                    if let Ok(Cw20ExecuteMsg::Transfer { amount, .. }) = from_binary(msg) {
+                        *outgoing.entry(contract_addr.clone()).or_default() += amount;
+                    }
+                }
+                // Already recorded as unbonding while the delegation is still queried
+                CosmosMsg::Staking(StakingMsg::Undelegate { amount, .. }) => {
+                    *outgoing.entry(amount.denom.clone()).or_default() += amount.amount;
+                }
+                // Rewards withdrawn by the message land in the bank balance
+                CosmosMsg::Distribution(DistributionMsg::WithdrawDelegatorReward { validator }) => {
+                    if let Some(delegation) = deps.querier.query_delegation(&env.contract.address, validator)? {
+                        for coin in delegation.accumulated_rewards {
+                            *incoming.entry(coin.denom).or_default() += coin.amount;
+                        }
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok((incoming, outgoing))
+    }
 
-    #[test]
-    fn mint() {
-        let mut deps = mock_dependencies();
-        //let contract: Cw721Contract<Extension, Empty> = cw721_base::Cw721Contract::default();
+    /// Delegator rewards as native assets, to pay them out or report them
+    fn delegator_metadata(rewards: Vec<Coin>) -> Metadata {
+        let mut metadata = Metadata::default();
+        metadata.set_native(rewards);
+        metadata
+    }
 
-        let info = mock_info(CREATOR, &[]);
-        let init_msg = InstantiateMsg {
-            name: "Greeks".to_string(),
-            symbol: "drachma".to_string(),
-            minter: CREATOR.to_string(),
-        };
-        entry::instantiate(deps.as_mut(), mock_env(), info.clone(), init_msg).unwrap();
+    /// Amount of `denom` in the native assets of `escrow`
+    fn native_amount(escrow: &Metadata, denom: &str) -> Uint128 {
+        escrow
+            .native_balance()
+            .into_iter()
+            .find(|coin| coin.denom == denom)
+            .map(|coin| coin.amount)
+            .unwrap_or_default()
+    }
 
-        let token_id = "1";
-        let token_uri = "json";
-        let mint_msg = MintMsg {
-            token_id: token_id.to_string(),
-            owner: "bob".to_string(),
-            token_uri: None,
-            extension: Metadata {
-                native: Some(coins(1000, "earth")),
-                cw20: None,
-            },
-        };
+    /// Amount of the cw20 at `contract_address` in the cw20 assets of `escrow`
+    fn cw20_amount(escrow: &Metadata, contract_address: &str) -> Uint128 {
+        escrow
+            .cw20_balance()
+            .into_iter()
+            .find(|cw20| cw20.contract_address == contract_address)
+            .map(|cw20| cw20.amount)
+            .unwrap_or_default()
+    }
 
-        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg.clone());
-        entry::execute(deps.as_mut(), mock_env(), info.clone(), exec_msg.into()).unwrap();
+    /// Event `wasm-angel_{action}` recording how the escrow of `token_id` changed.
+    /// Every token event has the same attributes: action, sender, token_id, old_native,
+    /// old_cw20, new_native and new_cw20. Balances are comma separated `{amount}{denom}`
+    /// and `{contract}:{amount}` entries, `none` when nothing is escrowed as the chain
+    /// rejects empty attribute values
+    fn token_event(action: &str, sender: &Addr, token_id: &str, old: &Metadata, new: &Metadata) -> Event {
+        Event::new(format!("angel_{}", action))
+            .add_attribute("action", action)
+            .add_attribute("sender", sender)
+            .add_attribute("token_id", token_id)
+            .add_attribute("old_native", native_attribute(old))
+            .add_attribute("old_cw20", cw20_attribute(old))
+            .add_attribute("new_native", native_attribute(new))
+            .add_attribute("new_cw20", cw20_attribute(new))
+    }
 
-        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: token_id.to_string() };
-        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
-        assert_eq!(res.token_uri, mint_msg.token_uri);
-        assert_eq!(res.extension, mint_msg.extension);
+    fn native_attribute(escrow: &Metadata) -> String {
+        balance_attribute(escrow.native_balance().iter().map(|coin| coin.to_string()).collect())
+    }
 
+    fn cw20_attribute(escrow: &Metadata) -> String {
+        balance_attribute(
+            escrow
+                .cw20_balance()
+                .iter()
+                .map(|cw20| format!("{}:{}", cw20.contract_address, cw20.amount))
+                .collect(),
+        )
     }
 
-    #[test]
-    fn mint_update_metadata() {
-        let mut deps = mock_dependencies();
-        //let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+    fn balance_attribute(entries: Vec<String>) -> String {
+        if entries.is_empty() {
+            "none".to_string()
+        } else {
+            entries.join(",")
+        }
+    }
 
-        let info = mock_info(CREATOR, &[]);
-        let init_msg = InstantiateMsg {
-            name: "Greeks".to_string(),
-            symbol: "drachma".to_string(),
-            minter: CREATOR.to_string(),
-        };
-        entry::instantiate(deps.as_mut(), mock_env(), info.clone(), init_msg).unwrap();
+    /// Loads a minted token, failing with `TokenNotFound` if it doesn't exist
+    fn load_token(storage: &dyn Storage, token_id: &str) -> Result<TokenInfo<Extension>, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        contract
+            .tokens
+            .may_load(storage, token_id)?
+            .ok_or_else(|| ContractError::TokenNotFound { token_id: token_id.to_string() })
+    }
 
-        let token_id = "1";
-        let token_uri = "json";
-        let mint_msg = MintMsg {
-            token_id: token_id.to_string(),
-            owner: "bob".to_string(),
-            token_uri: None,
-            extension: Metadata {
-                native: Some(coins(1000, "earth")),
-                cw20: None,
-            },
-        };
+    /// Balance of `denom` held by this contract. Unlike the bank balance, the
+    /// coins of the staking denom that are delegated or unbonding count as held
+    fn native_held(deps: Deps, env: &Env, denom: &str) -> StdResult<Uint128> {
+        let mut held = deps.querier.query_balance(&env.contract.address, denom)?.amount;
+        if CONFIG.load(deps.storage)?.staking_denom.as_deref() == Some(denom) {
+            for delegation in deps.querier.query_all_delegations(&env.contract.address)? {
+                if delegation.amount.denom == denom {
+                    held += delegation.amount.amount;
+                }
+            }
+            held += unbonding_total(deps.storage, &env.block)?;
+        }
+        Ok(held)
+    }
 
-        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg.clone());
-        entry::execute(deps.as_mut(), mock_env(), info.clone(), exec_msg.into()).unwrap();
+    /// Withdraws the rewards accumulated by the delegations of this contract and shares
+    /// them over the bonded principal of the tokens. Has to run before the bonded
+    /// principal of a token changes, and before anything touches the validators, as
+    /// delegating, undelegating and redelegating pay out the pending rewards too.
+    /// Runs once a block, the rewards still show up in queries until the end of it
+    fn harvest_delegator_rewards(deps: DepsMut, env: &Env) -> Result<Vec<CosmosMsg>, ContractError> {
+        if LAST_HARVEST.may_load(deps.storage)? == Some(env.block.height) {
+            return Ok(vec![]);
+        }
+        LAST_HARVEST.save(deps.storage, &env.block.height)?;
 
+        let delegations = VALIDATOR_DELEGATIONS
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        let total_bonded = delegations.iter().fold(Uint128::zero(), |total, (_, delegated)| total + *delegated);
+        let mut rewards = vec![];
+        let mut msgs = vec![];
+        for (validator, _) in delegations {
+            if let Some(delegation) = deps.querier.query_delegation(&env.contract.address, &validator)? {
+                if delegation.accumulated_rewards.iter().any(|coin| !coin.amount.is_zero()) {
+                    rewards.extend(delegation.accumulated_rewards);
+                    msgs.push(DistributionMsg::WithdrawDelegatorReward { validator }.into());
+                }
+            }
+        }
+        add_delegator_rewards(deps.storage, &normalize_coins(rewards), total_bonded)?;
+        Ok(msgs)
+    }
 
-        let old_metadata = Metadata {
-            native: Some(coins(1000, "earth")),
-            cw20: None,
-        };
+    /// Balance of this contract in the cw20 at `contract_address`
+    fn cw20_held(deps: Deps, env: &Env, contract_address: &str) -> StdResult<Uint128> {
+        let res: BalanceResponse = deps.querier.query_wasm_smart(
+            contract_address,
+            &Cw20QueryMsg::Balance { address: env.contract.address.to_string() },
+        )?;
+        Ok(res.balance)
+    }
 
-        let new_metadata = Metadata {
-            native: Some(coins(2000, "earth")),
-            cw20: None,
-        };
+    fn execute_mint(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        mut msg: MintMsg<Metadata>,
+    ) -> Result<Response, ContractError> {
+        // The minted token is a claim on its native coins, so they have to be sent along
+        let expected = msg.extension.native_balance();
+        let received = normalize_coins(info.funds.clone());
+        if expected != received {
+            return Err(ContractError::FundsMismatch { expected, received });
+        }
+        msg.extension.set_native(expected);
+        // Cw20 can't travel with the mint message, they are credited by the receive hook
+        if !msg.extension.cw20_balance().is_empty() {
+            return Err(ContractError::UnbackedCw20 {});
+        }
+        msg.extension.cw20 = None;
+        if let Some(vesting) = &msg.extension.vesting {
+            if vesting.start > vesting.cliff || vesting.cliff > vesting.end || vesting.start >= vesting.end {
+                return Err(ContractError::InvalidVesting {});
+            }
+        }
 
-        let exec_msg = crate::msg::ExecuteMsg::UpdateMetadata { 
-            token_id: token_id.to_string(), 
-            token_uri: token_uri.to_string(), 
-            metadata: new_metadata.clone() 
+        add_to_totals(deps.storage, &msg.extension)?;
+        checkpoint_rewards(deps.storage, &msg.token_id, Uint128::zero())?;
+        let owner = deps.api.addr_validate(&msg.owner)?;
+        let config = CONFIG.load(deps.storage)?;
+        update_power(deps.storage, env.block.height, &config, &owner, &Metadata::default(), &msg.extension)?;
+        snapshot_token(deps.storage, env.block.height, &msg.token_id, Some((&owner, &msg.extension)))?;
+        let event = token_event("mint", &info.sender, &msg.token_id, &Metadata::default(), &msg.extension);
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        Ok(contract.mint(deps, env, info, msg)?.add_event(event))
+    }
+
+    fn execute_burn(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let token = load_token(deps.storage, &token_id)?;
+        contract.check_can_send(deps.as_ref(), &env, &info, &token)?;
+        assert_not_locked(&env, &token_id, &token.extension)?;
+        assert_vested(deps.storage, &env, &token_id, &token.extension, &Metadata::default())?;
+        let stake = load_stake(deps.storage, &token_id, &env.block)?;
+        if !stake.locked().is_zero() {
+            return start_redemption(deps, &env, &info, "burn", &token_id, stake, None);
+        }
+
+        // Release the escrowed assets to whoever owned the token
+        let (escrow, msgs) = burn_and_release(deps, &env, &token_id, &token.owner)?;
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_event(token_event("burn", &info.sender, &token_id, &escrow, &Metadata::default()))
+            .add_attribute("action", "burn")
+            .add_attribute("sender", info.sender)
+            .add_attribute("token_id", token_id))
+    }
+
+    fn execute_redeem(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+        recipient: Option<String>,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let token = load_token(deps.storage, &token_id)?;
+        contract.check_can_send(deps.as_ref(), &env, &info, &token)?;
+        assert_not_locked(&env, &token_id, &token.extension)?;
+        assert_vested(deps.storage, &env, &token_id, &token.extension, &Metadata::default())?;
+        let recipient = recipient.map(|recipient| deps.api.addr_validate(&recipient)).transpose()?;
+        let stake = load_stake(deps.storage, &token_id, &env.block)?;
+        if !stake.locked().is_zero() {
+            return start_redemption(deps, &env, &info, "redeem", &token_id, stake, recipient);
+        }
+
+        let recipient = recipient.unwrap_or(token.owner);
+        let (escrow, msgs) = burn_and_release(deps, &env, &token_id, &recipient)?;
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_event(
+                token_event("redeem", &info.sender, &token_id, &escrow, &Metadata::default())
+                    .add_attribute("recipient", &recipient),
+            )
+            .add_attribute("action", "redeem")
+            .add_attribute("sender", info.sender)
+            .add_attribute("recipient", recipient)
+            .add_attribute("token_id", token_id))
+    }
+
+    fn execute_exit_early(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+        recipient: Option<String>,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let mut token = load_token(deps.storage, &token_id)?;
+        contract.check_can_send(deps.as_ref(), &env, &info, &token)?;
+        let config = CONFIG.load(deps.storage)?;
+        let early_exit = config.early_exit.clone().ok_or(ContractError::EarlyExitDisabled {})?;
+        if assert_not_locked(&env, &token_id, &token.extension).is_ok() {
+            return Err(ContractError::NotLocked { token_id });
+        }
+        assert_not_redeeming(deps.storage, &token_id)?;
+        assert_vested(deps.storage, &env, &token_id, &token.extension, &Metadata::default())?;
+        let recipient = match recipient {
+            Some(recipient) => deps.api.addr_validate(&recipient)?,
+            None => token.owner.clone(),
         };
 
-        entry::execute(deps.as_mut(), mock_env(), info, exec_msg.into()).unwrap();
+        // The penalty leaves the escrow first, the rest is released like on redeem
+        let old = token.extension.clone();
+        let mut penalty = Metadata::default();
+        penalty.set_native(old.native_balance().into_iter().map(|coin| Coin { amount: coin.amount * early_exit.penalty, ..coin }).collect());
+        penalty.set_cw20(old.cw20_balance().into_iter().map(|cw20| Cw20 { amount: cw20.amount * early_exit.penalty, ..cw20 }).collect());
+        token.extension.sub_native(penalty.native_balance())?;
+        token.extension.sub_cw20(penalty.cw20_balance())?;
+        checkpoint_rewards(deps.storage, &token_id, config.principal(&old))?;
+        contract.tokens.save(deps.storage, &token_id, &token)?;
+        sub_from_totals(deps.storage, &penalty)?;
+        update_power(deps.storage, env.block.height, &config, &token.owner, &old, &token.extension)?;
+        let (_, mut msgs) = burn_and_release(deps.branch(), &env, &token_id, &recipient)?;
 
-        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: token_id.to_string() };
-        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
-        assert_eq!(res.token_uri, Some(token_uri.to_string()));
-        assert_eq!(res.extension, new_metadata);
+        match early_exit.sink {
+            PenaltySink::Treasury { address } => msgs.extend(penalty.payout_msgs(&address)?),
+            PenaltySink::Stakers => {
+                // Joins the stream of the asset if one is running, so it pays out until the
+                // stream ends. Otherwise it is released at once to whoever still stakes, or
+                // to the next stakers
+                let now = env.block.time.seconds();
+                let assets = penalty
+                    .native_balance()
+                    .into_iter()
+                    .map(|coin| (coin.denom, false, coin.amount))
+                    .chain(penalty.cw20_balance().into_iter().map(|cw20| (cw20.contract_address, true, cw20.amount)));
+                for (asset, cw20, amount) in assets {
+                    fund_rewards(deps.storage, &asset, cw20, amount, now, 0)?;
+                }
+                let total_principal = total_principal(deps.storage, &config)?;
+                release_rewards(deps.storage, now, total_principal)?;
+            }
+        }
 
+        let event = token_event("exit_early", &info.sender, &token_id, &old, &Metadata::default())
+            .add_attribute("recipient", &recipient)
+            .add_attribute("penalty_native", native_attribute(&penalty))
+            .add_attribute("penalty_cw20", cw20_attribute(&penalty));
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_event(event)
+            .add_attribute("action", "exit_early")
+            .add_attribute("sender", info.sender)
+            .add_attribute("recipient", recipient)
+            .add_attribute("token_id", token_id))
     }
 
+    /// Fails with `InvalidPenalty` above a penalty of one, with `NoStakers` if the
+    /// penalty goes to the stakers of a collection that stakes nothing, and if the
+    /// treasury address is not valid
+    fn validate_early_exit(deps: Deps, config: &Config, early_exit: &EarlyExit) -> Result<(), ContractError> {
+        if early_exit.penalty > Decimal::one() {
+            return Err(ContractError::InvalidPenalty {});
+        }
+        match &early_exit.sink {
+            PenaltySink::Treasury { address } => {
+                deps.api.addr_validate(address)?;
+            }
+            PenaltySink::Stakers if config.staking_denom.is_none() => return Err(ContractError::NoStakers {}),
+            PenaltySink::Stakers => {}
+        }
+        Ok(())
+    }
 
+    /// Undelegates what is left of the principal of a token being burned or redeemed
+    /// and records the redemption, so `ClaimUnbonded` can pay it out once the whole
+    /// principal is back
+    fn start_redemption(
+        mut deps: DepsMut,
+        env: &Env,
+        info: &MessageInfo,
+        action: &str,
+        token_id: &str,
+        mut stake: TokenStake,
+        recipient: Option<Addr>,
+    ) -> Result<Response, ContractError> {
+        if stake.redemption.is_some() {
+            return Err(ContractError::TokenRedeeming { token_id: token_id.to_string() });
+        }
+        let config = CONFIG.load(deps.storage)?;
+        if !stake.delegated.is_zero() {
+            undelegate_stake(deps.storage, &config, token_id, &mut stake)?;
+        }
+        let release_at = stake.release_at(env.block.time);
+        stake.redemption = Some(Redemption { recipient: recipient.clone(), release_at });
+        save_stake(deps.storage, token_id, &stake)?;
+        let (msgs, slashes) = send_unbonding_batch(deps.branch(), env, &config)?;
+        let release_at = load_stake(deps.storage, token_id, &env.block)?.release_at(env.block.time);
+
+        let escrow = load_token(deps.storage, token_id)?.extension;
+        let mut event = token_event("unbond", &info.sender, token_id, &escrow, &escrow)
+            .add_attribute("release_at", release_at.to_string());
+        if let Some(recipient) = &recipient {
+            event = event.add_attribute("recipient", recipient);
+        }
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_event(event)
+            .add_events(slashes)
+            .add_attribute("action", action)
+            .add_attribute("sender", &info.sender)
+            .add_attribute("token_id", token_id)
+            .add_attribute("release_at", release_at.to_string()))
+    }
+
+    fn execute_claim_unbonded(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+    ) -> Result<Response, ContractError> {
+        let token = load_token(deps.storage, &token_id)?;
+        let redemption = match load_stake(deps.storage, &token_id, &env.block)?.redemption {
+            Some(redemption) => redemption,
+            None => return Err(ContractError::NotRedeeming { token_id }),
+        };
+        if !redemption.release_at.is_expired(&env.block) {
+            return Err(ContractError::StillUnbonding { token_id, release_at: redemption.release_at });
+        }
+
+        // Burns started by the owner pay whoever owns the token now
+        let recipient = redemption.recipient.unwrap_or(token.owner);
+        let (escrow, msgs) = burn_and_release(deps, &env, &token_id, &recipient)?;
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_event(
+                token_event("claim_unbonded", &info.sender, &token_id, &escrow, &Metadata::default())
+                    .add_attribute("recipient", &recipient),
+            )
+            .add_attribute("action", "claim_unbonded")
+            .add_attribute("sender", info.sender)
+            .add_attribute("recipient", recipient)
+            .add_attribute("token_id", token_id))
+    }
+
+    /// Burns the token and returns its released escrow with the messages paying it,
+    /// and the token's pending rewards, out to `recipient`. Callers check the sender
+    /// may do so
+    fn burn_and_release(
+        deps: DepsMut,
+        env: &Env,
+        token_id: &str,
+        recipient: &Addr,
+    ) -> Result<(Metadata, Vec<CosmosMsg>), ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let token = load_token(deps.storage, token_id)?;
+        let config = CONFIG.load(deps.storage)?;
+        assert_unlocked(deps.as_ref(), env, &config, token_id, &Metadata::default())?;
+
+        contract.tokens.remove(deps.storage, token_id)?;
+        contract.decrement_tokens(deps.storage)?;
+        sub_from_totals(deps.storage, &token.extension)?;
+        update_power(deps.storage, env.block.height, &config, &token.owner, &token.extension, &Metadata::default())?;
+        snapshot_token(deps.storage, env.block.height, token_id, None)?;
+        let bonded = load_stake(deps.storage, token_id, &env.block)?.bonded();
+        TOKEN_STAKES.remove(deps.storage, token_id);
+        AUTO_COMPOUND.remove(deps.storage, token_id);
+        VESTING_WITHDRAWN.remove(deps.storage, token_id);
+        // The pending rewards leave with the escrow
+        let principal = config.principal(&token.extension);
+        let mut rewards = close_rewards(deps.storage, token_id, principal)?;
+        rewards.merge(&delegator_metadata(close_delegator_rewards(deps.storage, token_id, bonded)?));
+
+        let mut msgs = token.extension.payout_msgs(recipient.as_str())?;
+        msgs.extend(rewards.payout_msgs(recipient.as_str())?);
+        Ok((token.extension, msgs))
+    }
+
+    fn execute_deposit(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+    ) -> Result<Response, ContractError> {
+        let funds = normalize_coins(info.funds.clone());
+        if funds.is_empty() {
+            return Err(ContractError::NoFunds {});
+        }
+        check_can_deposit(deps.as_ref(), &env, &info, &token_id)?;
+
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let old = load_token(deps.storage, &token_id)?.extension;
+        let config = CONFIG.load(deps.storage)?;
+        checkpoint_rewards(deps.storage, &token_id, config.principal(&old))?;
+        let token = contract
+            .tokens
+            .update(deps.storage, &token_id, |token| match token {
+                Some(mut token_info) => {
+                    let mut native = token_info.extension.native_balance();
+                    native.extend(funds.clone());
+                    token_info.extension.set_native(native);
+                    Ok(token_info)
+                },
+                None => Err(ContractError::TokenNotFound { token_id: token_id.clone() }),
+            })?;
+        add_to_totals(deps.storage, &Metadata { native: Some(funds), cw20: None, locked_until: None, vesting: None })?;
+        update_power(deps.storage, env.block.height, &config, &token.owner, &old, &token.extension)?;
+        snapshot_token(deps.storage, env.block.height, &token_id, Some((&token.owner, &token.extension)))?;
+
+        Ok(Response::new()
+            .add_event(token_event("deposit", &info.sender, &token_id, &old, &token.extension))
+            .add_attribute("action", "deposit")
+            .add_attribute("sender", info.sender)
+            .add_attribute("token_id", token_id))
+    }
+
+    /// Enforces the configured `DepositPolicy` for `info.sender` depositing into `token_id`
+    fn check_can_deposit(
+        deps: Deps,
+        env: &Env,
+        info: &MessageInfo,
+        token_id: &str,
+    ) -> Result<(), ContractError> {
+        assert_not_redeeming(deps.storage, token_id)?;
+        match CONFIG.load(deps.storage)?.deposit_policy {
+            DepositPolicy::Anyone => Ok(()),
+            DepositPolicy::OwnerOrApproved => {
+                let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+                let token = load_token(deps.storage, token_id)?;
+                Ok(contract.check_can_send(deps, env, info, &token)?)
+            }
+        }
+    }
+
+    fn execute_withdraw(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+        native: Vec<Coin>,
+        cw20: Vec<Cw20>,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let old = load_token(deps.storage, &token_id)?;
+        contract.check_can_send(deps.as_ref(), &env, &info, &old)?;
+        assert_not_redeeming(deps.storage, &token_id)?;
+        assert_not_locked(&env, &token_id, &old.extension)?;
+        let config = CONFIG.load(deps.storage)?;
+
+        let mut token = old.clone();
+        token.extension.sub_native(native.clone())?;
+        token.extension.sub_cw20(cw20.clone())?;
+        assert_unlocked(deps.as_ref(), &env, &config, &token_id, &token.extension)?;
+        assert_vested(deps.storage, &env, &token_id, &old.extension, &token.extension)?;
+        checkpoint_rewards(deps.storage, &token_id, config.principal(&old.extension))?;
+        contract.tokens.save(deps.storage, &token_id, &token)?;
+        update_power(deps.storage, env.block.height, &config, &token.owner, &old.extension, &token.extension)?;
+        snapshot_token(deps.storage, env.block.height, &token_id, Some((&token.owner, &token.extension)))?;
+
+        // Withdrawn assets always go to the owner, like the escrow released on burn
+        let withdrawn = Metadata {
+            native: Some(native),
+            cw20: Some(cw20),
+            locked_until: None,
+            vesting: None,
+        };
+        sub_from_totals(deps.storage, &withdrawn)?;
+        if old.extension.vesting.is_some() {
+            VESTING_WITHDRAWN.update(deps.storage, &token_id, |total| -> StdResult<_> {
+                let mut total = total.unwrap_or_default();
+                total.merge(&withdrawn);
+                Ok(total)
+            })?;
+        }
+        Ok(Response::new()
+            .add_messages(withdrawn.payout_msgs(token.owner.as_str())?)
+            .add_event(token_event("withdraw", &info.sender, &token_id, &old.extension, &token.extension))
+            .add_attribute("action", "withdraw")
+            .add_attribute("sender", info.sender)
+            .add_attribute("owner", token.owner)
+            .add_attribute("token_id", token_id))
+    }
+
+    fn execute_merge(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_ids: Vec<String>,
+        into: String,
+    ) -> Result<Response, ContractError> {
+        if token_ids.is_empty() {
+            return Err(ContractError::NothingToMerge {});
+        }
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let mut target = load_token(deps.storage, &into)?;
+        contract.check_can_send(deps.as_ref(), &env, &info, &target)?;
+        assert_not_redeeming(deps.storage, &into)?;
+        // The vesting schedule of the target would cover the merged assets too
+        assert_vested(deps.storage, &env, &into, &target.extension, &Metadata::default())?;
+        let old = target.extension.clone();
+        let config = CONFIG.load(deps.storage)?;
+        checkpoint_rewards(deps.storage, &into, config.principal(&old))?;
+
+        let mut seen: Vec<&str> = vec![&into];
+        let mut events = vec![];
+        for token_id in &token_ids {
+            if seen.contains(&token_id.as_str()) {
+                return Err(ContractError::DuplicateTokenId { token_id: token_id.clone() });
+            }
+            seen.push(token_id);
+
+            let source = load_token(deps.storage, token_id)?;
+            contract.check_can_send(deps.as_ref(), &env, &info, &source)?;
+            assert_not_redeeming(deps.storage, token_id)?;
+            assert_vested(deps.storage, &env, token_id, &source.extension, &Metadata::default())?;
+            // A lock-up at a height and one at a time can't be told apart in the merge,
+            // so only the target may still be locked
+            assert_not_locked(&env, token_id, &source.extension)?;
+            // Only positions held by the same account can be consolidated
+            if source.owner != target.owner {
+                return Err(ContractError::OwnerMismatch { token_id: token_id.clone() });
+            }
+            target.extension.merge(&source.extension);
+            events.push(token_event("merge", &info.sender, token_id, &source.extension, &Metadata::default()));
+        }
+        events.push(token_event("merge", &info.sender, &into, &old, &target.extension));
+
+        // Rewards of the merged tokens are paid out rather than carried over,
+        // their delegations move along with the principal
+        let mut rewards = Metadata::default();
+        let mut stake = load_stake(deps.storage, &into, &env.block)?;
+        let sources = token_ids
+            .iter()
+            .map(|token_id| load_stake(deps.storage, token_id, &env.block))
+            .collect::<StdResult<Vec<_>>>()?;
+        let mut msgs = vec![];
+        if !stake.bonded().is_zero() || sources.iter().any(|source| !source.bonded().is_zero()) {
+            msgs = harvest_delegator_rewards(deps.branch(), &env)?;
+            accrue_delegator_rewards(deps.storage, &into, stake.bonded())?;
+        }
+        for (token_id, source_stake) in token_ids.iter().zip(sources) {
+            let source = contract.tokens.load(deps.storage, token_id)?;
+            rewards.merge(&close_rewards(deps.storage, token_id, config.principal(&source.extension))?);
+            rewards.merge(&delegator_metadata(close_delegator_rewards(deps.storage, token_id, source_stake.bonded())?));
+            update_power(deps.storage, env.block.height, &config, &source.owner, &source.extension, &Metadata::default())?;
+            snapshot_token(deps.storage, env.block.height, token_id, None)?;
+            stake.merge(&source_stake);
+            if BATCH_TOKENS.has(deps.storage, token_id) {
+                BATCH_TOKENS.remove(deps.storage, token_id);
+                BATCH_TOKENS.save(deps.storage, &into, &())?;
+            }
+            TOKEN_STAKES.remove(deps.storage, token_id);
+            AUTO_COMPOUND.remove(deps.storage, token_id);
+            VESTING_WITHDRAWN.remove(deps.storage, token_id);
+            contract.tokens.remove(deps.storage, token_id)?;
+            contract.decrement_tokens(deps.storage)?;
+        }
+        save_stake(deps.storage, &into, &stake)?;
+        contract.tokens.save(deps.storage, &into, &target)?;
+        update_power(deps.storage, env.block.height, &config, &target.owner, &old, &target.extension)?;
+        snapshot_token(deps.storage, env.block.height, &into, Some((&target.owner, &target.extension)))?;
+
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_messages(rewards.payout_msgs(target.owner.as_str())?)
+            .add_events(events)
+            .add_attribute("action", "merge")
+            .add_attribute("sender", info.sender)
+            .add_attribute("token_ids", token_ids.join(","))
+            .add_attribute("token_id", into))
+    }
+
+    fn execute_split(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+        parts: Vec<Metadata>,
+    ) -> Result<Response, ContractError> {
+        if parts.len() < 2 {
+            return Err(ContractError::TooFewParts {});
+        }
+        if parts.len() > MAX_SPLIT_PARTS {
+            return Err(ContractError::TooManyParts { max: MAX_SPLIT_PARTS as u32 });
+        }
+        if parts.iter().any(|part| part.native_balance().is_empty() && part.cw20_balance().is_empty()) {
+            return Err(ContractError::EmptyPart {});
+        }
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let source = load_token(deps.storage, &token_id)?;
+        contract.check_can_send(deps.as_ref(), &env, &info, &source)?;
+        assert_not_redeeming(deps.storage, &token_id)?;
+        assert_vested(deps.storage, &env, &token_id, &source.extension, &Metadata::default())?;
+
+        let mut total = Metadata::default();
+        for part in &parts {
+            total.merge(part);
+        }
+        if !total.same_balance(&source.extension) {
+            return Err(ContractError::SplitMismatch { token_id });
+        }
+        // Delegations can't be divided between the parts
+        let config = CONFIG.load(deps.storage)?;
+        assert_unlocked(deps.as_ref(), &env, &config, &token_id, &Metadata::default())?;
+
+        contract.tokens.remove(deps.storage, &token_id)?;
+        contract.decrement_tokens(deps.storage)?;
+        let bonded = load_stake(deps.storage, &token_id, &env.block)?.bonded();
+        TOKEN_STAKES.remove(deps.storage, &token_id);
+        AUTO_COMPOUND.remove(deps.storage, &token_id);
+        VESTING_WITHDRAWN.remove(deps.storage, &token_id);
+        // Rewards of the split token are paid out, the parts start accruing from now
+        let principal = config.principal(&source.extension);
+        let mut rewards = close_rewards(deps.storage, &token_id, principal)?;
+        rewards.merge(&delegator_metadata(close_delegator_rewards(deps.storage, &token_id, bonded)?));
+        update_power(deps.storage, env.block.height, &config, &source.owner, &source.extension, &Metadata::default())?;
+        snapshot_token(deps.storage, env.block.height, &token_id, None)?;
+
+        // New ids are derived from the source id and the position of the part
+        let mut new_ids = vec![];
+        let mut events = vec![token_event("split", &info.sender, &token_id, &source.extension, &Metadata::default())];
+        for (i, part) in parts.into_iter().enumerate() {
+            let new_id = format!("{}-{}", token_id, i + 1);
+            // The parts stay locked as long as the source was
+            let mut extension = Metadata::default();
+            extension.merge(&part);
+            extension.locked_until = source.extension.locked_until;
+            extension.vesting = source.extension.vesting.clone();
+            events.push(token_event("split", &info.sender, &new_id, &Metadata::default(), &extension));
+            update_power(deps.storage, env.block.height, &config, &source.owner, &Metadata::default(), &extension)?;
+            snapshot_token(deps.storage, env.block.height, &new_id, Some((&source.owner, &extension)))?;
+            let token = TokenInfo {
+                owner: source.owner.clone(),
+                approvals: vec![],
+                token_uri: source.token_uri.clone(),
+                extension,
+            };
+            contract
+                .tokens
+                .update(deps.storage, &new_id, |old| match old {
+                    Some(_) => Err(ContractError::TokenExists { token_id: new_id.clone() }),
+                    None => Ok(token),
+                })?;
+            checkpoint_rewards(deps.storage, &new_id, Uint128::zero())?;
+            contract.increment_tokens(deps.storage)?;
+            new_ids.push(new_id);
+        }
+
+        Ok(Response::new()
+            .add_messages(rewards.payout_msgs(source.owner.as_str())?)
+            .add_events(events)
+            .add_attribute("action", "split")
+            .add_attribute("sender", info.sender)
+            .add_attribute("token_id", token_id)
+            .add_attribute("token_ids", new_ids.join(",")))
+    }
+
+    fn execute_receive(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        wrapper: Cw20ReceiveMsg,
+    ) -> Result<Response, ContractError> {
+        // info.sender is the cw20 contract that moved the tokens to us
+        match from_binary(&wrapper.msg)? {
+            ReceiveMsg::DepositToToken { token_id } => {
+                // Any contract can call the hook, only listed cw20s are taken into escrow
+                let config = CONFIG.load(deps.storage)?;
+                if !config.accepted_cw20s.iter().any(|accepted| accepted == info.sender.as_str()) {
+                    return Err(ContractError::InvalidCw20 { contract_address: info.sender.to_string() });
+                }
+                let depositor = MessageInfo {
+                    sender: deps.api.addr_validate(&wrapper.sender)?,
+                    funds: vec![],
+                };
+                check_can_deposit(deps.as_ref(), &env, &depositor, &token_id)?;
+
+                let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+                let old = load_token(deps.storage, &token_id)?.extension;
+                let token = contract
+                    .tokens
+                    .update(deps.storage, &token_id, |token| match token {
+                        Some(mut token_info) => {
+                            token_info.extension.add_cw20(info.sender.as_str(), wrapper.amount);
+                            Ok(token_info)
+                        },
+                        None => Err(ContractError::TokenNotFound { token_id: token_id.clone() }),
+                    })?;
+                let mut deposited = Metadata::default();
+                deposited.add_cw20(info.sender.as_str(), wrapper.amount);
+                add_to_totals(deps.storage, &deposited)?;
+                update_power(deps.storage, env.block.height, &config, &token.owner, &old, &token.extension)?;
+                snapshot_token(deps.storage, env.block.height, &token_id, Some((&token.owner, &token.extension)))?;
+                Ok(Response::new()
+                    .add_event(token_event("deposit_to_token", &depositor.sender, &token_id, &old, &token.extension))
+                    .add_attribute("action", "deposit_to_token")
+                    .add_attribute("sender", wrapper.sender)
+                    .add_attribute("cw20", info.sender)
+                    .add_attribute("amount", wrapper.amount)
+                    .add_attribute("token_id", token_id))
+            }
+            ReceiveMsg::FundRewards { duration } => {
+                let mut rewards = Metadata::default();
+                rewards.add_cw20(info.sender.as_str(), wrapper.amount);
+                let sender = deps.api.addr_validate(&wrapper.sender)?;
+                add_rewards(deps, &env, "fund_rewards", &sender, rewards, duration)
+            }
+        }
+    }
+
+    fn execute_distribute_rewards(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+    ) -> Result<Response, ContractError> {
+        let rewards = Metadata {
+            native: Some(info.funds.clone()),
+            cw20: None,
+            locked_until: None,
+            vesting: None,
+        };
+        add_rewards(deps, &env, "distribute_rewards", &info.sender, rewards, 0)
+    }
+
+    fn execute_fund_rewards(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        denom: String,
+        duration: u64,
+    ) -> Result<Response, ContractError> {
+        let funds = normalize_coins(info.funds.clone());
+        if let Some(coin) = funds.iter().find(|coin| coin.denom != denom) {
+            return Err(ContractError::InvalidRewardDenom { denom: coin.denom.clone() });
+        }
+        let rewards = Metadata {
+            native: Some(funds),
+            cw20: None,
+            locked_until: None,
+            vesting: None,
+        };
+        add_rewards(deps, &env, "fund_rewards", &info.sender, rewards, duration)
+    }
+
+    /// Adds `rewards` to their reward pools, streamed over the next `duration` seconds
+    /// or distributed right away if it is zero, within the bounds running streams set
+    fn add_rewards(
+        deps: DepsMut,
+        env: &Env,
+        action: &str,
+        sender: &Addr,
+        rewards: Metadata,
+        duration: u64,
+    ) -> Result<Response, ContractError> {
+        if rewards.native_balance().is_empty() && rewards.cw20_balance().is_empty() {
+            return Err(ContractError::NoFunds {});
+        }
+        let config = CONFIG.load(deps.storage)?;
+        let total_principal = total_principal(deps.storage, &config)?;
+        if total_principal.is_zero() {
+            return Err(ContractError::NoPrincipal {});
+        }
+
+        let now = env.block.time.seconds();
+        let mut end = now;
+        let assets = rewards
+            .native_balance()
+            .into_iter()
+            .map(|coin| (coin.denom, false, coin.amount))
+            .chain(rewards.cw20_balance().into_iter().map(|cw20| (cw20.contract_address, true, cw20.amount)));
+        for (asset, cw20, amount) in assets {
+            if !config.reward_denoms.contains(&asset) {
+                return Err(ContractError::InvalidRewardDenom { denom: asset });
+            }
+            end = end.max(fund_rewards(deps.storage, &asset, cw20, amount, now, duration)?);
+        }
+        release_rewards(deps.storage, now, total_principal)?;
+
+        let event = Event::new(format!("angel_{}", action))
+            .add_attribute("action", action)
+            .add_attribute("sender", sender)
+            .add_attribute("rewards_native", native_attribute(&rewards))
+            .add_attribute("rewards_cw20", cw20_attribute(&rewards))
+            .add_attribute("stream_end", end.to_string());
+        Ok(Response::new()
+            .add_event(event)
+            .add_attribute("action", action)
+            .add_attribute("sender", sender))
+    }
+
+    fn execute_claim_rewards(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_ids: Vec<String>,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let config = CONFIG.load(deps.storage)?;
+        let mut res = Response::new();
+        for token_id in &token_ids {
+            let token = load_token(deps.storage, token_id)?;
+            contract.check_can_send(deps.as_ref(), &env, &info, &token)?;
+            let bonded = load_stake(deps.storage, token_id, &env.block)?.bonded();
+            // Rewards always go to the owner, like withdrawals
+            let mut rewards = claim_rewards(deps.storage, token_id, config.principal(&token.extension))?;
+            rewards.merge(&delegator_metadata(take_or_close_delegator_rewards(deps.storage, token_id, bonded)?));
+            let event = token_event("claim_rewards", &info.sender, token_id, &token.extension, &token.extension)
+                .add_attribute("rewards_native", native_attribute(&rewards))
+                .add_attribute("rewards_cw20", cw20_attribute(&rewards));
+            res = res
+                .add_messages(rewards.payout_msgs(token.owner.as_str())?)
+                .add_event(event);
+        }
+
+        Ok(res
+            .add_attribute("action", "claim_rewards")
+            .add_attribute("sender", info.sender)
+            .add_attribute("token_ids", token_ids.join(",")))
+    }
+
+    fn execute_delegate(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let token = load_token(deps.storage, &token_id)?;
+        contract.check_can_send(deps.as_ref(), &env, &info, &token)?;
+        assert_not_redeeming(deps.storage, &token_id)?;
+
+        let config = CONFIG.load(deps.storage)?;
+        let mut stake = load_stake(deps.storage, &token_id, &env.block)?;
+        let amount = config.principal(&token.extension).saturating_sub(stake.locked());
+        let denom = match &config.staking_denom {
+            Some(denom) if !amount.is_zero() => denom,
+            _ => return Err(ContractError::NothingToDelegate { token_id }),
+        };
+        let mut msgs = harvest_delegator_rewards(deps.branch(), &env)?;
+        accrue_delegator_rewards(deps.storage, &token_id, stake.bonded())?;
+        for (validator, amount) in spread(amount, &config.validators)? {
+            VALIDATOR_DELEGATIONS.update(deps.storage, &validator, |delegated| -> StdResult<_> {
+                Ok(delegated.unwrap_or_default() + amount)
+            })?;
+            msgs.push(StakingMsg::Delegate { validator, amount: Coin { denom: denom.clone(), amount } }.into());
+        }
+        stake.delegated += amount;
+        save_stake(deps.storage, &token_id, &stake)?;
+
+        let event = token_event("delegate", &info.sender, &token_id, &token.extension, &token.extension)
+            .add_attribute("amount", amount);
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_event(event)
+            .add_attribute("action", "delegate")
+            .add_attribute("sender", info.sender)
+            .add_attribute("token_id", token_id))
+    }
+
+    fn execute_undelegate(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let token = load_token(deps.storage, &token_id)?;
+        contract.check_can_send(deps.as_ref(), &env, &info, &token)?;
+        assert_not_redeeming(deps.storage, &token_id)?;
+
+        let config = CONFIG.load(deps.storage)?;
+        let mut stake = load_stake(deps.storage, &token_id, &env.block)?;
+        let amount = stake.delegated;
+        if config.staking_denom.is_none() || amount.is_zero() {
+            return Err(ContractError::NothingToUndelegate { token_id });
+        }
+        undelegate_stake(deps.storage, &config, &token_id, &mut stake)?;
+        save_stake(deps.storage, &token_id, &stake)?;
+        let (msgs, slashes) = send_unbonding_batch(deps.branch(), &env, &config)?;
+
+        let release_at = load_stake(deps.storage, &token_id, &env.block)?.release_at(env.block.time);
+        let event = token_event("undelegate", &info.sender, &token_id, &token.extension, &token.extension)
+            .add_attribute("amount", amount)
+            .add_attribute("release_at", release_at.to_string());
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_event(event)
+            .add_events(slashes)
+            .add_attribute("action", "undelegate")
+            .add_attribute("sender", info.sender)
+            .add_attribute("token_id", token_id))
+    }
+
+    fn execute_unbond(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+    ) -> Result<Response, ContractError> {
+        let config = CONFIG.load(deps.storage)?;
+        let amount = UNBONDING_BATCH.may_load(deps.storage)?.unwrap_or_default().amount;
+        let (msgs, slashes) = send_unbonding_batch(deps.branch(), &env, &config)?;
+        if msgs.is_empty() {
+            return Err(ContractError::NoBatchDue {});
+        }
+
+        let release_at = Expiration::AtTime(env.block.time.plus_seconds(config.unbonding_period));
+        let event = Event::new("angel_unbond")
+            .add_attribute("action", "unbond")
+            .add_attribute("sender", &info.sender)
+            .add_attribute("amount", amount)
+            .add_attribute("release_at", release_at.to_string());
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_event(event)
+            .add_events(slashes)
+            .add_attribute("action", "unbond")
+            .add_attribute("sender", info.sender))
+    }
+
+    fn execute_set_auto_compound(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+        auto_compound: Option<bool>,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let token = load_token(deps.storage, &token_id)?;
+        contract.check_can_send(deps.as_ref(), &env, &info, &token)?;
+        match auto_compound {
+            Some(auto_compound) => AUTO_COMPOUND.save(deps.storage, &token_id, &auto_compound)?,
+            None => AUTO_COMPOUND.remove(deps.storage, &token_id),
+        }
+
+        let config = CONFIG.load(deps.storage)?;
+        let auto_compound = auto_compounds(deps.storage, &config, &token_id)?;
+        let event = token_event("set_auto_compound", &info.sender, &token_id, &token.extension, &token.extension)
+            .add_attribute("auto_compound", auto_compound.to_string());
+        Ok(Response::new()
+            .add_event(event)
+            .add_attribute("action", "set_auto_compound")
+            .add_attribute("sender", info.sender)
+            .add_attribute("token_id", token_id))
+    }
+
+    fn execute_compound(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let config = CONFIG.load(deps.storage)?;
+        // Withdrawn rewards land in the contract balance before the messages below run
+        let mut msgs = harvest_delegator_rewards(deps.branch(), &env)?;
+        // Tokens that stopped staking take what is left with `ClaimRewards`
+        let limit = limit.unwrap_or(DEFAULT_COMPOUND_LIMIT).min(MAX_COMPOUND_LIMIT) as usize;
+        let start = start_after.as_deref().map(Bound::exclusive);
+        let token_ids = TOKEN_STAKES
+            .keys(deps.storage, start, None, Order::Ascending)
+            .take(limit)
+            .collect::<StdResult<Vec<_>>>()?;
+        let last_token_id = token_ids.last().cloned().unwrap_or_default();
+
+        let mut rewards = vec![];
+        let mut restaked = Uint128::zero();
+        let mut events = vec![];
+        for token_id in token_ids {
+            events.extend(settle_slash(deps.storage, &env, &token_id)?);
+            let mut stake = load_stake(deps.storage, &token_id, &env.block)?;
+            let share = take_or_close_delegator_rewards(deps.storage, &token_id, stake.bonded())?;
+            if share.is_empty() {
+                continue;
+            }
+            rewards.extend(share.clone());
+            let mut token = load_token(deps.storage, &token_id)?;
+            checkpoint_rewards(deps.storage, &token_id, config.principal(&token.extension))?;
+            // Tokens no longer delegated can't restake, they get rewards to claim instead
+            if stake.delegated.is_zero() || !auto_compounds(deps.storage, &config, &token_id)? {
+                for coin in share {
+                    credit_rewards(deps.storage, &token_id, &coin.denom, coin.amount)?;
+                }
+                continue;
+            }
+
+            let old = token.extension.clone();
+            let compounded = Metadata { native: Some(share), cw20: None, locked_until: None, vesting: None };
+            token.extension.merge(&compounded);
+            contract.tokens.save(deps.storage, &token_id, &token)?;
+            add_to_totals(deps.storage, &compounded)?;
+            update_power(deps.storage, env.block.height, &config, &token.owner, &old, &token.extension)?;
+            snapshot_token(deps.storage, env.block.height, &token_id, Some((&token.owner, &token.extension)))?;
+            // The staking denom goes straight back to the validators
+            let amount = config.principal(&compounded);
+            stake.delegated += amount;
+            restaked += amount;
+            save_stake(deps.storage, &token_id, &stake)?;
+            events.push(token_event("compound", &info.sender, &token_id, &old, &token.extension));
+        }
+        if rewards.is_empty() {
+            return Err(ContractError::NothingToCompound {});
+        }
+        if !restaked.is_zero() {
+            let denom = config.staking_denom.clone().unwrap_or_default();
+            for (validator, amount) in spread(restaked, &config.validators)? {
+                VALIDATOR_DELEGATIONS.update(deps.storage, &validator, |delegated| -> StdResult<_> {
+                    Ok(delegated.unwrap_or_default() + amount)
+                })?;
+                msgs.push(StakingMsg::Delegate { validator, amount: Coin { denom: denom.clone(), amount } }.into());
+            }
+        }
+
+        let rewards = delegator_metadata(rewards);
+        let event = Event::new("angel_compound")
+            .add_attribute("action", "compound")
+            .add_attribute("sender", &info.sender)
+            .add_attribute("rewards_native", native_attribute(&rewards))
+            .add_attribute("restaked", restaked);
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_event(event)
+            .add_events(events)
+            .add_attribute("action", "compound")
+            .add_attribute("sender", info.sender)
+            .add_attribute("last_token_id", last_token_id))
+    }
+
+    /// Takes out the delegator rewards of `token_id`, dropping its checkpoints once
+    /// nothing is bonded so tokens that stopped staking leave no state behind
+    fn take_or_close_delegator_rewards(storage: &mut dyn Storage, token_id: &str, bonded: Uint128) -> StdResult<Vec<Coin>> {
+        if bonded.is_zero() {
+            close_delegator_rewards(storage, token_id, bonded)
+        } else {
+            take_delegator_rewards(storage, token_id, bonded)
+        }
+    }
+
+    /// Queues everything `stake` has delegated for the next unbonding batch, as an
+    /// unbonding entry with no release time yet. The caller saves `stake` before
+    /// sending the batch
+    fn undelegate_stake(
+        storage: &mut dyn Storage,
+        config: &Config,
+        token_id: &str,
+        stake: &mut TokenStake,
+    ) -> Result<(), ContractError> {
+        if config.staking_denom.is_none() {
+            return Err(ContractError::NothingToUndelegate { token_id: token_id.to_string() });
+        }
+        let mut batch = UNBONDING_BATCH.may_load(storage)?.unwrap_or_default();
+        batch.amount += stake.delegated;
+        UNBONDING_BATCH.save(storage, &batch)?;
+        BATCH_TOKENS.save(storage, token_id, &())?;
+        stake.unbonding.push(Unbonding { amount: stake.delegated, release_at: Expiration::Never {} });
+        stake.delegated = Uint128::zero();
+        Ok(())
+    }
+
+    /// Undelegates the queued principal once an unbonding epoch passed since the last
+    /// batch, taking from the largest validator delegations first, and starts the
+    /// unbonding of every token in the batch, which apply their slashes first. Returns
+    /// no messages if no batch is due, along with the slash events
+    fn send_unbonding_batch(mut deps: DepsMut, env: &Env, config: &Config) -> Result<(Vec<CosmosMsg>, Vec<Event>), ContractError> {
+        let batch = UNBONDING_BATCH.may_load(deps.storage)?.unwrap_or_default();
+        let now = env.block.time.seconds();
+        if batch.amount.is_zero() || now < batch.last_sent.saturating_add(config.unbonding_epoch()) {
+            return Ok((vec![], vec![]));
+        }
+
+        let mut msgs = harvest_delegator_rewards(deps.branch(), env)?;
+        let storage = deps.storage;
+        let denom = config.staking_denom.clone().unwrap_or_default();
+        let mut delegations = VALIDATOR_DELEGATIONS
+            .range(storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        delegations.sort_by_key(|(_, delegated)| std::cmp::Reverse(*delegated));
+        let mut left = batch.amount;
+        for (validator, delegated) in delegations {
+            if left.is_zero() {
+                break;
+            }
+            let amount = left.min(delegated);
+            left -= amount;
+            if amount == delegated {
+                VALIDATOR_DELEGATIONS.remove(storage, &validator);
+            } else {
+                VALIDATOR_DELEGATIONS.save(storage, &validator, &(delegated - amount))?;
+            }
+            msgs.push(StakingMsg::Undelegate { validator, amount: Coin { denom: denom.clone(), amount } }.into());
+        }
+
+        let release_at = Expiration::AtTime(env.block.time.plus_seconds(config.unbonding_period));
+        push_unbonding(storage, Unbonding { amount: batch.amount, release_at }, &env.block)?;
+        let token_ids = BATCH_TOKENS.keys(storage, None, None, Order::Ascending).collect::<StdResult<Vec<_>>>()?;
+        let mut events = vec![];
+        for token_id in token_ids {
+            BATCH_TOKENS.remove(storage, &token_id);
+            events.extend(settle_slash(storage, env, &token_id)?);
+            let mut stake = match TOKEN_STAKES.may_load(storage, &token_id)? {
+                Some(stake) => stake,
+                None => continue,
+            };
+            accrue_delegator_rewards(storage, &token_id, stake.bonded())?;
+            for unbonding in stake.unbonding.iter_mut().filter(|unbonding| unbonding.queued()) {
+                unbonding.release_at = release_at;
+            }
+            let release_at = stake.release_at(env.block.time);
+            if let Some(redemption) = &mut stake.redemption {
+                redemption.release_at = release_at;
+            }
+            TOKEN_STAKES.save(storage, &token_id, &stake)?;
+        }
+        UNBONDING_BATCH.save(storage, &UnbondingBatch { amount: Uint128::zero(), last_sent: now })?;
+        Ok((msgs, events))
+    }
+
+    /// Brings the recorded delegations down to what the validators report and lowers
+    /// the slash index by what was slashed, along with the undelegations waiting for
+    /// the next batch. Tokens apply the slash to their escrow with `settle_slash`.
+    /// Slashes of undelegations already unbonding can't be queried and are not covered
+    fn reconcile_delegations(deps: DepsMut, env: &Env) -> Result<Option<Event>, ContractError> {
+        let mut loss = Uint128::zero();
+        let mut total_bonded = Uint128::zero();
+        let delegations = VALIDATOR_DELEGATIONS
+            .range(deps.storage, None, None, Order::Ascending)
+            .collect::<StdResult<Vec<_>>>()?;
+        for (validator, recorded) in delegations {
+            total_bonded += recorded;
+            let actual = deps
+                .querier
+                .query_delegation(&env.contract.address, &validator)?
+                .map(|delegation| delegation.amount.amount)
+                .unwrap_or_default();
+            if actual >= recorded {
+                continue;
+            }
+            loss += recorded - actual;
+            if actual.is_zero() {
+                VALIDATOR_DELEGATIONS.remove(deps.storage, &validator);
+            } else {
+                VALIDATOR_DELEGATIONS.save(deps.storage, &validator, &actual)?;
+            }
+        }
+        if loss.is_zero() {
+            return Ok(None);
+        }
+
+        let mut index = slash_index(deps.storage)?;
+        index.slash(loss, total_bonded);
+        SLASH_INDEX.save(deps.storage, &index)?;
+        let mut batch = UNBONDING_BATCH.may_load(deps.storage)?.unwrap_or_default();
+        batch.amount = batch.amount.multiply_ratio(total_bonded - loss.min(total_bonded), total_bonded);
+        UNBONDING_BATCH.save(deps.storage, &batch)?;
+        Ok(Some(Event::new("angel_slash")
+            .add_attribute("action", "slash")
+            .add_attribute("amount", loss)
+            .add_attribute("era", index.era.to_string())
+            .add_attribute("ratio", index.ratio.to_string())))
+    }
+
+    /// Applies the slashes since `token_id` last did to its bonded principal and takes
+    /// what was lost out of its escrow. Runs before anything reads or changes the stake
+    /// or the principal of a token, returns the slash event if the token lost anything
+    fn settle_slash(storage: &mut dyn Storage, env: &Env, token_id: &str) -> Result<Option<Event>, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let mut token = match contract.tokens.may_load(storage, token_id)? {
+            Some(token) => token,
+            None => return Ok(None),
+        };
+        let now = slash_index(storage)?;
+        let checkpoint = SLASH_CHECKPOINTS.may_load(storage, token_id)?;
+        if checkpoint.as_ref() == Some(&now) {
+            return Ok(None);
+        }
+        SLASH_CHECKPOINTS.save(storage, token_id, &now)?;
+        // A token without a checkpoint never bonded anything
+        let (checkpoint, mut stake) = match (checkpoint, TOKEN_STAKES.may_load(storage, token_id)?) {
+            (Some(checkpoint), Some(stake)) => (checkpoint, stake),
+            _ => return Ok(None),
+        };
+        let loss = stake.slash(&checkpoint, &now);
+        if loss.is_zero() {
+            return Ok(None);
+        }
+        save_stake(storage, token_id, &stake)?;
+
+        let config = CONFIG.load(storage)?;
+        let old = token.extension.clone();
+        checkpoint_rewards(storage, token_id, config.principal(&old))?;
+        let denom = config.staking_denom.clone().unwrap_or_default();
+        let slashed = Metadata { native: Some(vec![Coin { denom, amount: loss }]), cw20: None, locked_until: None, vesting: None };
+        token.extension.sub_native(slashed.native_balance())?;
+        contract.tokens.save(storage, token_id, &token)?;
+        sub_from_totals(storage, &slashed)?;
+        update_power(storage, env.block.height, &config, &token.owner, &old, &token.extension)?;
+        snapshot_token(storage, env.block.height, token_id, Some((&token.owner, &token.extension)))?;
+        Ok(Some(token_event("slash", &env.contract.address, token_id, &old, &token.extension).add_attribute("amount", loss)))
+    }
+
+    /// Tokens whose stake or principal `msg` reads or changes
+    fn staked_tokens(msg: &ExecuteMsg<Metadata>) -> Vec<String> {
+        match msg {
+            ExecuteMsg::Burn { token_id }
+            | ExecuteMsg::Redeem { token_id, .. }
+            | ExecuteMsg::ExitEarly { token_id, .. }
+            | ExecuteMsg::ClaimUnbonded { token_id }
+            | ExecuteMsg::UpdateMetadata { token_id, .. }
+            | ExecuteMsg::Deposit { token_id }
+            | ExecuteMsg::Withdraw { token_id, .. }
+            | ExecuteMsg::Split { token_id, .. }
+            | ExecuteMsg::Delegate { token_id }
+            | ExecuteMsg::Undelegate { token_id } => vec![token_id.clone()],
+            ExecuteMsg::Merge { token_ids, into } => token_ids.iter().chain(Some(into)).cloned().collect(),
+            ExecuteMsg::ClaimRewards { token_ids } => token_ids.clone(),
+            ExecuteMsg::Receive(wrapper) => match from_binary(&wrapper.msg) {
+                Ok(ReceiveMsg::DepositToToken { token_id }) => vec![token_id],
+                _ => vec![],
+            },
+            _ => vec![],
+        }
+    }
+
+    /// Fails with `TokenRedeeming` once `token_id` waits for `ClaimUnbonded`
+    fn assert_not_redeeming(storage: &dyn Storage, token_id: &str) -> Result<(), ContractError> {
+        match TOKEN_STAKES.may_load(storage, token_id)? {
+            Some(TokenStake { redemption: Some(_), .. }) => {
+                Err(ContractError::TokenRedeeming { token_id: token_id.to_string() })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Fails with `TokenLocked` while the lock-up of the token is running
+    fn assert_not_locked(env: &Env, token_id: &str, escrow: &Metadata) -> Result<(), ContractError> {
+        match escrow.locked_until {
+            Some(locked_until) if !locked_until.is_expired(&env.block) => {
+                Err(ContractError::TokenLocked { token_id: token_id.to_string(), locked_until })
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// Assets of `escrow`, escrowed in `token_id`, that vested and did not vest yet.
+    /// What was withdrawn counts as vested
+    fn vesting_status(storage: &dyn Storage, env: &Env, token_id: &str, escrow: &Metadata) -> StdResult<(Metadata, Metadata)> {
+        let mut total = Metadata::default();
+        total.merge(escrow);
+        let vesting = match &escrow.vesting {
+            Some(vesting) => vesting,
+            None => return Ok((total, Metadata::default())),
+        };
+        total.merge(&VESTING_WITHDRAWN.may_load(storage, token_id)?.unwrap_or_default());
+
+        let now = env.block.time;
+        let (mut vested, mut unvested) = (Metadata::default(), Metadata::default());
+        vested.set_native(total.native_balance().into_iter().map(|coin| Coin { amount: vesting.vested(coin.amount, now), ..coin }).collect());
+        vested.set_cw20(total.cw20_balance().into_iter().map(|cw20| Cw20 { amount: vesting.vested(cw20.amount, now), ..cw20 }).collect());
+        unvested.merge(&total);
+        unvested.sub_native(vested.native_balance()).map_err(|err| StdError::generic_err(err.to_string()))?;
+        unvested.sub_cw20(vested.cw20_balance()).map_err(|err| StdError::generic_err(err.to_string()))?;
+        Ok((vested, unvested))
+    }
+
+    /// Fails with `Unvested` if `escrow`, what `token_id` is left with, holds less of
+    /// any asset than has not vested yet from `old`, its escrow until now
+    fn assert_vested(
+        storage: &dyn Storage,
+        env: &Env,
+        token_id: &str,
+        old: &Metadata,
+        escrow: &Metadata,
+    ) -> Result<(), ContractError> {
+        let (_, unvested) = vesting_status(storage, env, token_id, old)?;
+        let mut left = escrow.clone();
+        if left.sub_native(unvested.native_balance()).is_err() || left.sub_cw20(unvested.cw20_balance()).is_err() {
+            return Err(ContractError::Unvested { token_id: token_id.to_string() });
+        }
+        Ok(())
+    }
+
+    /// Tokens being redeemed or still locked up only change hands if the collection
+    /// allows it
+    fn assert_transferable(deps: Deps, env: &Env, token_id: &str) -> Result<(), ContractError> {
+        let config = CONFIG.load(deps.storage)?;
+        if config.locked_transfers == TransferPolicy::Locked {
+            assert_not_locked(env, token_id, &load_token(deps.storage, token_id)?.extension)?;
+        }
+        if config.unbonding_transfers == TransferPolicy::Locked {
+            assert_not_redeeming(deps.storage, token_id)?;
+        }
+        Ok(())
+    }
+
+    /// Fails with `PrincipalLocked` if `escrow`, the escrow `token_id` is left with,
+    /// holds less than the token's delegated and unbonding principal
+    fn assert_unlocked(
+        deps: Deps,
+        env: &Env,
+        config: &Config,
+        token_id: &str,
+        escrow: &Metadata,
+    ) -> Result<(), ContractError> {
+        if config.principal(escrow) < load_stake(deps.storage, token_id, &env.block)?.locked() {
+            return Err(ContractError::PrincipalLocked { token_id: token_id.to_string() });
+        }
+        Ok(())
+    }
+
+    /// Fails with `UnknownValidator` unless every address is an active validator
+    fn validate_validators(deps: Deps, validators: &[String]) -> Result<(), ContractError> {
+        for validator in validators {
+            if deps.querier.query_validator(validator)?.is_none() {
+                return Err(ContractError::UnknownValidator { validator: validator.clone() });
+            }
+        }
+        Ok(())
+    }
+
+    /// Splits `amount` evenly over `validators`, the remainder going to the first one
+    fn spread(amount: Uint128, validators: &[String]) -> Result<Vec<(String, Uint128)>, ContractError> {
+        if validators.is_empty() {
+            return Err(ContractError::NoValidators {});
+        }
+        let share = amount / Uint128::from(validators.len() as u128);
+        let mut remainder = amount - share * Uint128::from(validators.len() as u128);
+        Ok(validators
+            .iter()
+            .map(|validator| (validator.clone(), share + std::mem::take(&mut remainder)))
+            .filter(|(_, amount)| !amount.is_zero())
+            .collect())
+    }
+
+    /// Moves the delegations of validators missing from `validators` over to the new set
+    fn redelegate_msgs(storage: &mut dyn Storage, validators: &[String], denom: &str) -> Result<Vec<CosmosMsg>, ContractError> {
+        let dropped = VALIDATOR_DELEGATIONS
+            .range(storage, None, None, Order::Ascending)
+            .filter(|item| !matches!(item, Ok((validator, _)) if validators.contains(validator)))
+            .collect::<StdResult<Vec<_>>>()?;
+        let mut msgs = vec![];
+        for (src_validator, delegated) in dropped {
+            VALIDATOR_DELEGATIONS.remove(storage, &src_validator);
+            for (dst_validator, amount) in spread(delegated, validators)? {
+                VALIDATOR_DELEGATIONS.update(storage, &dst_validator, |delegated| -> StdResult<_> {
+                    Ok(delegated.unwrap_or_default() + amount)
+                })?;
+                msgs.push(StakingMsg::Redelegate {
+                    src_validator: src_validator.clone(),
+                    dst_validator,
+                    amount: Coin { denom: denom.to_string(), amount },
+                }.into());
+            }
+        }
+        Ok(msgs)
+    }
+
+    fn execute_update_config(
+        mut deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        update: ConfigUpdate,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let minter = contract.minter.load(deps.storage)?;
+        if info.sender != minter {
+            return Err(ContractError::Unauthorized {});
+        }
+
+        let mut config = CONFIG.load(deps.storage)?;
+        if let Some(deposit_policy) = update.deposit_policy {
+            config.deposit_policy = deposit_policy;
+        }
+        if let Some(metadata_policy) = update.metadata_policy {
+            // Holders rely on balances no longer being editable
+            if config.metadata_policy == MetadataPolicy::NonFinancial && metadata_policy != MetadataPolicy::NonFinancial {
+                return Err(ContractError::MetadataPolicyLocked {});
+            }
+            config.metadata_policy = metadata_policy;
+        }
+        if let Some(reward_denoms) = update.reward_denoms {
+            config.reward_denoms = reward_denoms;
+        }
+        if let Some(accepted_cw20s) = update.accepted_cw20s {
+            for contract_address in &accepted_cw20s {
+                deps.api.addr_validate(contract_address)?;
+            }
+            config.accepted_cw20s = accepted_cw20s;
+        }
+        let mut msgs = vec![];
+        if let Some(validators) = update.validators {
+            validate_validators(deps.as_ref(), &validators)?;
+            msgs = harvest_delegator_rewards(deps.branch(), &env)?;
+            msgs.extend(redelegate_msgs(deps.storage, &validators, config.staking_denom.as_deref().unwrap_or_default())?);
+            config.validators = validators;
+        }
+        if let Some(unbonding_period) = update.unbonding_period {
+            config.unbonding_period = unbonding_period;
+        }
+        if let Some(unbonding_transfers) = update.unbonding_transfers {
+            config.unbonding_transfers = unbonding_transfers;
+        }
+        if let Some(locked_transfers) = update.locked_transfers {
+            config.locked_transfers = locked_transfers;
+        }
+        match (update.early_exit, update.disable_early_exit) {
+            (Some(_), Some(true)) => return Err(ContractError::EarlyExitConflict {}),
+            (Some(early_exit), _) => {
+                validate_early_exit(deps.as_ref(), &config, &early_exit)?;
+                config.early_exit = Some(early_exit);
+            }
+            (None, Some(true)) => config.early_exit = None,
+            (None, _) => {}
+        }
+        if let Some(auto_compound) = update.auto_compound {
+            config.auto_compound = auto_compound;
+        }
+        CONFIG.save(deps.storage, &config)?;
+
+        // Not tied to a token, so it only reports the resulting policies
+        let event = Event::new("angel_update_config")
+            .add_attribute("action", "update_config")
+            .add_attribute("sender", &info.sender)
+            .add_attribute("deposit_policy", format!("{:?}", config.deposit_policy))
+            .add_attribute("metadata_policy", format!("{:?}", config.metadata_policy))
+            .add_attribute("unbonding_transfers", format!("{:?}", config.unbonding_transfers))
+            .add_attribute("locked_transfers", format!("{:?}", config.locked_transfers));
+        Ok(Response::new()
+            .add_messages(msgs)
+            .add_event(event)
+            .add_attribute("action", "update_config")
+            .add_attribute("sender", info.sender))
+    }
+
+    fn execute_update_metadata(
+        deps: DepsMut,
+        env: Env,
+        info: MessageInfo,
+        token_id: String,
+        token_uri: Option<String>,
+        native: Option<Vec<Coin>>,
+        cw20: Option<Vec<Cw20>>,
+    ) -> Result<Response, ContractError> {
+        let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+        let minter = contract.minter.load(deps.storage)?;
+        if info.sender != minter {
+            Err(ContractError::Unauthorized {})
+        } else {
+            let config = CONFIG.load(deps.storage)?;
+            let old_metadata = load_token(deps.storage, &token_id)?.extension;
+            assert_not_redeeming(deps.storage, &token_id)?;
+            let locked = load_stake(deps.storage, &token_id, &env.block)?.locked();
+            checkpoint_rewards(deps.storage, &token_id, config.principal(&old_metadata))?;
+            let token = contract
+                .tokens
+                .update(deps.storage, &token_id, |token| match token {
+                    Some(mut token_info) => {
+                        if let Some(token_uri) = token_uri {
+                            token_info.token_uri = if token_uri.is_empty() { None } else { Some(token_uri) };
+                        }
+                        if let Some(native) = native {
+                            token_info.extension.set_native(native);
+                        }
+                        if let Some(cw20) = cw20 {
+                            token_info.extension.set_cw20(cw20);
+                        }
+                        if config.metadata_policy == MetadataPolicy::NonFinancial && !token_info.extension.same_balance(&old_metadata) {
+                            return Err(ContractError::FinancialMetadataUpdate {});
+                        }
+                        if config.principal(&token_info.extension) < locked {
+                            return Err(ContractError::PrincipalLocked { token_id: token_id.clone() });
+                        }
+                        Ok(token_info)
+                    },
+                    None => Err(ContractError::TokenNotFound { token_id: token_id.clone() }),
+                })?;
+            sub_from_totals(deps.storage, &old_metadata)?;
+            add_to_totals(deps.storage, &token.extension)?;
+            update_power(deps.storage, env.block.height, &config, &token.owner, &old_metadata, &token.extension)?;
+            snapshot_token(deps.storage, env.block.height, &token_id, Some((&token.owner, &token.extension)))?;
+            Ok(Response::new()
+                .add_event(token_event("update_metadata", &info.sender, &token_id, &old_metadata, &token.extension))
+                .add_attribute("action", "update_metadata")
+                .add_attribute("sender", info.sender)
+                .add_attribute("token_id", token_id))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use cosmwasm_std::testing::{mock_dependencies, mock_dependencies_with_balance, mock_env, mock_info, MockApi, MockQuerier, MockStorage, MOCK_CONTRACT_ADDR};
+    use cosmwasm_std::{attr, coin, coins, from_binary, ContractResult, Decimal, Env, Event, FullDelegation, Order, Validator, OwnedDeps, SubMsg, SystemError, SystemResult, WasmQuery};
+    use cw20::Cw20ReceiveMsg;
+    use cw721::NftInfoResponse;
+    const CREATOR: &str = "creator";
+
+    /// Mock dependencies where the contract already holds plenty of every asset the
+    /// tests escrow, as if the funds attached to each message had arrived
+    fn funded_dependencies() -> OwnedDeps<MockStorage, MockApi, MockQuerier> {
+        let mut deps = mock_dependencies_with_balance(&[
+            coin(1_000_000, "air"),
+            coin(1_000_000, "earth"),
+            coin(1_000_000, "fire"),
+        ]);
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { .. } => SystemResult::Ok(ContractResult::Ok(
+                to_binary(&cw20::BalanceResponse { balance: Uint128::new(1_000_000) }).unwrap(),
+            )),
+            _ => SystemResult::Err(SystemError::UnsupportedRequest { kind: "wasm".to_string() }),
+        });
+        deps
+    }
+
+    /// Makes the mock chain report the delegations the contract recorded, as if its
+    /// staking messages had gone through
+    fn sync_delegations(deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, validators: &[Validator]) {
+        sync_delegations_with_rewards(deps, validators, &[]);
+    }
+
+    /// Like `sync_delegations`, with `rewards` accumulated on every delegation
+    fn sync_delegations_with_rewards(
+        deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>,
+        validators: &[Validator],
+        rewards: &[Coin],
+    ) {
+        let delegations: Vec<FullDelegation> = VALIDATOR_DELEGATIONS
+            .range(&deps.storage, None, None, Order::Ascending)
+            .map(|item| {
+                let (validator, amount) = item.unwrap();
+                FullDelegation {
+                    delegator: Addr::unchecked(MOCK_CONTRACT_ADDR),
+                    validator,
+                    amount: coin(amount.u128(), "earth"),
+                    can_redelegate: coin(amount.u128(), "earth"),
+                    accumulated_rewards: rewards.to_vec(),
+                }
+            })
+            .collect();
+        deps.querier.update_staking("earth", validators, &delegations);
+    }
+
+    #[test]
+    fn mint() {
+        let mut deps = funded_dependencies();
+        //let contract: Cw721Contract<Extension, Empty> = cw721_base::Cw721Contract::default();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info.clone(), init_msg).unwrap();
+
+        let token_id = "1";
+        let mint_msg = MintMsg {
+            token_id: token_id.to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+
+        let info = mock_info(CREATOR, &coins(1000, "earth"));
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg.clone());
+        entry::execute(deps.as_mut(), mock_env(), info, exec_msg).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: token_id.to_string() };
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.token_uri, mint_msg.token_uri);
+        assert_eq!(res.extension, mint_msg.extension);
+
+    }
+
+    #[test]
+    fn mint_update_metadata() {
+        let mut deps = funded_dependencies();
+        //let contract: Cw721Contract<Extension, Empty, Empty, Empty> = cw721_base::Cw721Contract::default();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info.clone(), init_msg).unwrap();
+
+        let token_id = "1";
+        let token_uri = "json";
+        let mint_msg = MintMsg {
+            token_id: token_id.to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+
+        let new_metadata = Metadata {
+            native: Some(coins(2000, "earth")),
+            cw20: None,
+            locked_until: None,
+            vesting: None,
+        };
+
+        let exec_msg = crate::msg::ExecuteMsg::UpdateMetadata { 
+            token_id: token_id.to_string(), 
+            token_uri: Some(token_uri.to_string()), 
+            native: new_metadata.native.clone(),
+            cw20: None,
+        };
+
+        entry::execute(deps.as_mut(), mock_env(), info, exec_msg).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: token_id.to_string() };
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.token_uri, Some(token_uri.to_string()));
+        assert_eq!(res.extension, new_metadata);
+
+    }
+
+    #[test]
+    fn mint_requires_exact_native_funds() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(vec![coin(600, "earth"), coin(400, "earth"), coin(5, "fire")]),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+
+        // nothing sent
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg.clone());
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::FundsMismatch {
+            expected: vec![coin(1000, "earth"), coin(5, "fire")],
+            received: vec![],
+        });
+
+        // too much of one denom
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg.clone());
+        let info = mock_info(CREATOR, &[coin(1001, "earth"), coin(5, "fire")]);
+        let err = entry::execute(deps.as_mut(), mock_env(), info, exec_msg).unwrap_err();
+        assert!(matches!(err, ContractError::FundsMismatch { .. }));
+
+        // same funds in a different order are accepted and stored normalized
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        let info = mock_info(CREATOR, &[coin(5, "fire"), coin(1000, "earth")]);
+        entry::execute(deps.as_mut(), mock_env(), info, exec_msg).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1".to_string() };
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.extension.native, Some(vec![coin(1000, "earth"), coin(5, "fire")]));
+    }
+
+    #[test]
+    fn burn_releases_escrow_to_owner() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+
+        // only the owner (or someone approved) can burn
+        let exec_msg = crate::msg::ExecuteMsg::Burn { token_id: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+
+        let exec_msg = crate::msg::ExecuteMsg::Burn { token_id: "1".to_string() };
+        let res = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(BankMsg::Send {
+            to_address: "bob".to_string(),
+            amount: coins(1000, "earth"),
+        })]);
+
+        let query_msg = crate::msg::QueryMsg::NumTokens {};
+        let res : cw721::NumTokensResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.count, 0);
+    }
+
+    #[test]
+    fn redeem_pays_out_to_recipient() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            accepted_cw20s: Some(vec!["obol".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+
+        let exec_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "bob".to_string(),
+            amount: Uint128::new(30),
+            msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "1".to_string() }).unwrap(),
+        });
+        entry::execute(deps.as_mut(), mock_env(), mock_info("obol", &[]), exec_msg).unwrap();
+
+        let exec_msg = crate::msg::ExecuteMsg::Redeem { token_id: "1".to_string(), recipient: Some("carol".to_string()) };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("carol", &[]), exec_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+
+        let res = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send {
+                to_address: "carol".to_string(),
+                amount: coins(1000, "earth"),
+            }),
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: "obol".to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer { recipient: "carol".to_string(), amount: Uint128::new(30) }).unwrap(),
+                funds: vec![],
+            }),
+        ]);
+
+        // the token is gone
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1".to_string() };
+        entry::query(deps.as_ref(), mock_env(), query_msg).unwrap_err();
+    }
+
+    #[test]
+    fn withdraw_part_of_escrow() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            accepted_cw20s: Some(vec!["obol".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(vec![coin(1000, "earth"), coin(10, "fire")]),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        let info = mock_info(CREATOR, &[coin(1000, "earth"), coin(10, "fire")]);
+        entry::execute(deps.as_mut(), mock_env(), info, exec_msg).unwrap();
+
+        let exec_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "bob".to_string(),
+            amount: Uint128::new(30),
+            msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "1".to_string() }).unwrap(),
+        });
+        entry::execute(deps.as_mut(), mock_env(), mock_info("obol", &[]), exec_msg).unwrap();
+
+        // can't take more than the token holds
+        let exec_msg = crate::msg::ExecuteMsg::Withdraw {
+            token_id: "1".to_string(),
+            native: coins(11, "fire"),
+            cw20: vec![],
+        };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::Overdraft {
+            asset: "fire".to_string(),
+            available: Uint128::new(10),
+            requested: Uint128::new(11),
+        });
+
+        let exec_msg = crate::msg::ExecuteMsg::Withdraw {
+            token_id: "1".to_string(),
+            native: vec![coin(400, "earth"), coin(10, "fire")],
+            cw20: vec![Cw20 { contract_address: "obol".to_string(), amount: Uint128::new(30) }],
+        };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), exec_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+
+        let res = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+        assert_eq!(res.messages.len(), 2);
+        assert_eq!(res.messages[0], SubMsg::new(BankMsg::Send {
+            to_address: "bob".to_string(),
+            amount: vec![coin(400, "earth"), coin(10, "fire")],
+        }));
+
+        // emptied entries are removed
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1".to_string() };
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.extension, Metadata {
+            native: Some(coins(600, "earth")),
+            cw20: None,
+            locked_until: None,
+            vesting: None,
+        });
+    }
+
+    #[test]
+    fn non_financial_metadata_policy() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            metadata_policy: Some(MetadataPolicy::NonFinancial),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+
+        // balances stay put, even if the contract could back the new ones
+        let exec_msg = crate::msg::ExecuteMsg::UpdateMetadata {
+            token_id: "1".to_string(),
+            token_uri: Some("json".to_string()),
+            native: Some(coins(2000, "earth")),
+            cw20: None,
+        };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::FinancialMetadataUpdate {});
+
+        let exec_msg = crate::msg::ExecuteMsg::UpdateMetadata {
+            token_id: "1".to_string(),
+            token_uri: Some("json".to_string()),
+            native: Some(coins(1000, "earth")),
+            cw20: None,
+        };
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap();
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1".to_string() };
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.token_uri, Some("json".to_string()));
+
+        // and the minter can't lift the restriction
+        let config_msg = crate::msg::ExecuteMsg::UpdateConfig(crate::msg::ConfigUpdate {
+            metadata_policy: Some(MetadataPolicy::Unrestricted),
+            ..Default::default()
+        });
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), config_msg).unwrap_err();
+        assert_eq!(err, ContractError::MetadataPolicyLocked {});
+    }
+
+    #[test]
+    fn update_metadata_patches_fields() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: Some("json".to_string()),
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+
+        // only cw20 is touched, the uri and native coins are kept
+        let exec_msg = crate::msg::ExecuteMsg::UpdateMetadata {
+            token_id: "1".to_string(),
+            token_uri: None,
+            native: None,
+            cw20: Some(vec![Cw20 { contract_address: "obol".to_string(), amount: Uint128::new(30) }]),
+        };
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1".to_string() };
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg.clone()).unwrap()).unwrap();
+        assert_eq!(res.token_uri, Some("json".to_string()));
+        assert_eq!(res.extension, Metadata {
+            native: Some(coins(1000, "earth")),
+            cw20: Some(vec![Cw20 { contract_address: "obol".to_string(), amount: Uint128::new(30) }]),
+            locked_until: None,
+            vesting: None,
+        });
+
+        // empty values clear
+        let exec_msg = crate::msg::ExecuteMsg::UpdateMetadata {
+            token_id: "1".to_string(),
+            token_uri: Some(String::new()),
+            native: Some(vec![]),
+            cw20: None,
+        };
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap();
+
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.token_uri, None);
+        assert_eq!(res.extension, Metadata {
+            native: None,
+            cw20: Some(vec![Cw20 { contract_address: "obol".to_string(), amount: Uint128::new(30) }]),
+            locked_until: None,
+            vesting: None,
+        });
+
+        let query_msg = crate::msg::QueryMsg::CollectionInfo {};
+        let res : crate::msg::CollectionInfoResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.native, vec![]);
+        assert_eq!(res.cw20, vec![Cw20 { contract_address: "obol".to_string(), amount: Uint128::new(30) }]);
+    }
+
+    #[test]
+    fn deposit_respects_policy() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            accepted_cw20s: Some(vec!["obol".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+
+        let exec_msg = crate::msg::ExecuteMsg::Deposit { token_id: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), exec_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::NoFunds {});
+
+        // anyone can deposit by default, duplicate denoms are merged
+        let info = mock_info("alice", &[coin(500, "earth"), coin(3, "air")]);
+        entry::execute(deps.as_mut(), mock_env(), info, exec_msg.clone()).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1".to_string() };
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.extension.native, Some(vec![coin(3, "air"), coin(1500, "earth")]));
+
+        // only the minter can restrict deposits
+        let config_msg = crate::msg::ExecuteMsg::UpdateConfig(crate::msg::ConfigUpdate {
+            deposit_policy: Some(DepositPolicy::OwnerOrApproved),
+            ..Default::default()
+        });
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), config_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unauthorized {});
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), config_msg).unwrap();
+
+        let info = mock_info("alice", &coins(500, "earth"));
+        let err = entry::execute(deps.as_mut(), mock_env(), info, exec_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+        let cw20_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "alice".to_string(),
+            amount: Uint128::new(30),
+            msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "1".to_string() }).unwrap(),
+        });
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("obol", &[]), cw20_msg).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &coins(500, "earth")), exec_msg).unwrap();
+    }
+
+    #[test]
+    fn missing_token_errors() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            accepted_cw20s: Some(vec!["obol".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let not_found = ContractError::TokenNotFound { token_id: "7".to_string() };
+        let exec_msg = crate::msg::ExecuteMsg::UpdateMetadata {
+            token_id: "7".to_string(),
+            token_uri: Some("json".to_string()),
+            native: None,
+            cw20: None,
+        };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap_err();
+        assert_eq!(err, not_found);
+
+        let exec_msg = crate::msg::ExecuteMsg::Deposit { token_id: "7".to_string() };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("alice", &coins(500, "earth")), exec_msg).unwrap_err();
+        assert_eq!(err, not_found);
+
+        let exec_msg = crate::msg::ExecuteMsg::Withdraw { token_id: "7".to_string(), native: coins(1, "earth"), cw20: vec![] };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, not_found);
+
+        let exec_msg = crate::msg::ExecuteMsg::Redeem { token_id: "7".to_string(), recipient: None };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, not_found);
+
+        let cw20_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "alice".to_string(),
+            amount: Uint128::new(30),
+            msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "7".to_string() }).unwrap(),
+        });
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("obol", &[]), cw20_msg).unwrap_err();
+        assert_eq!(err, not_found);
+    }
+
+    #[test]
+    fn handlers_emit_angel_events() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            accepted_cw20s: Some(vec!["obol".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        let res = entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+        assert_eq!(res.events, vec![Event::new("angel_mint").add_attributes(vec![
+            attr("action", "mint"),
+            attr("sender", CREATOR),
+            attr("token_id", "1"),
+            attr("old_native", "none"),
+            attr("old_cw20", "none"),
+            attr("new_native", "1000earth"),
+            attr("new_cw20", "none"),
+        ])]);
+
+        let cw20_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "alice".to_string(),
+            amount: Uint128::new(30),
+            msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "1".to_string() }).unwrap(),
+        });
+        let res = entry::execute(deps.as_mut(), mock_env(), mock_info("obol", &[]), cw20_msg).unwrap();
+        assert_eq!(res.events, vec![Event::new("angel_deposit_to_token").add_attributes(vec![
+            attr("action", "deposit_to_token"),
+            attr("sender", "alice"),
+            attr("token_id", "1"),
+            attr("old_native", "1000earth"),
+            attr("old_cw20", "none"),
+            attr("new_native", "1000earth"),
+            attr("new_cw20", "obol:30"),
+        ])]);
+
+        let exec_msg = crate::msg::ExecuteMsg::UpdateMetadata {
+            token_id: "1".to_string(),
+            token_uri: None,
+            native: Some(vec![coin(900, "earth"), coin(5, "air")]),
+            cw20: None,
+        };
+        let res = entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap();
+        assert_eq!(res.events, vec![Event::new("angel_update_metadata").add_attributes(vec![
+            attr("action", "update_metadata"),
+            attr("sender", CREATOR),
+            attr("token_id", "1"),
+            attr("old_native", "1000earth"),
+            attr("old_cw20", "obol:30"),
+            attr("new_native", "5air,900earth"),
+            attr("new_cw20", "obol:30"),
+        ])]);
+
+        let exec_msg = crate::msg::ExecuteMsg::Redeem { token_id: "1".to_string(), recipient: Some("carol".to_string()) };
+        let res = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+        assert_eq!(res.events, vec![Event::new("angel_redeem").add_attributes(vec![
+            attr("action", "redeem"),
+            attr("sender", "bob"),
+            attr("token_id", "1"),
+            attr("old_native", "5air,900earth"),
+            attr("old_cw20", "obol:30"),
+            attr("new_native", "none"),
+            attr("new_cw20", "none"),
+            attr("recipient", "carol"),
+        ])]);
+    }
+
+    #[test]
+    fn rewards_accrue_by_principal() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            staking_denom: Some("earth".to_string()),
+            reward_denoms: Some(vec!["fire".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let exec_msg = crate::msg::ExecuteMsg::DistributeRewards {};
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("treasury", &coins(400, "fire")), exec_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::NoPrincipal {});
+
+        for (token_id, owner, amount) in [("1", "bob", 1000), ("2", "alice", 3000)] {
+            let mint_msg = MintMsg {
+                token_id: token_id.to_string(),
+                owner: owner.to_string(),
+                token_uri: None,
+                extension: Metadata {
+                    native: Some(vec![coin(amount, "earth"), coin(7, "air")]),
+                    cw20: None,
+                    locked_until: None,
+                    vesting: None,
+                },
+            };
+            let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+            let info = mock_info(CREATOR, &[coin(amount, "earth"), coin(7, "air")]);
+            entry::execute(deps.as_mut(), mock_env(), info, exec_msg).unwrap();
+        }
+
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("treasury", &coins(400, "air")), exec_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::InvalidRewardDenom { denom: "air".to_string() });
+        entry::execute(deps.as_mut(), mock_env(), mock_info("treasury", &coins(400, "fire")), exec_msg.clone()).unwrap();
+
+        // topping up only counts for rewards distributed afterwards
+        let deposit_msg = crate::msg::ExecuteMsg::Deposit { token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &coins(2000, "earth")), deposit_msg).unwrap();
+        entry::execute(deps.as_mut(), mock_env(), mock_info("treasury", &coins(600, "fire")), exec_msg).unwrap();
+
+        let pending = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, token_id: &str| {
+            let query_msg = crate::msg::QueryMsg::PendingRewards { token_id: token_id.to_string() };
+            let res: crate::msg::PendingRewardsResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+            res.native
+        };
+        assert_eq!(pending(&deps, "1"), coins(400, "fire"));
+        assert_eq!(pending(&deps, "2"), coins(600, "fire"));
+
+        let claim_msg = crate::msg::ExecuteMsg::ClaimRewards { token_ids: vec!["1".to_string()] };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), claim_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+        let res = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), claim_msg).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(BankMsg::Send {
+            to_address: "bob".to_string(),
+            amount: coins(400, "fire"),
+        })]);
+        assert_eq!(pending(&deps, "1"), vec![]);
+
+        // redeeming pays the rewards along with the escrow
+        let exec_msg = crate::msg::ExecuteMsg::Redeem { token_id: "2".to_string(), recipient: None };
+        let res = entry::execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), exec_msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: vec![coin(7, "air"), coin(3000, "earth")],
+            }),
+            SubMsg::new(BankMsg::Send {
+                to_address: "alice".to_string(),
+                amount: coins(600, "fire"),
+            }),
+        ]);
+
+        let query_msg = crate::msg::QueryMsg::Solvency {};
+        let res : crate::msg::SolvencyResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.rewards_native, vec![]);
+    }
+
+    #[test]
+    fn funded_rewards_stream_linearly() {
+        let mut deps = funded_dependencies();
+        let at = |seconds: u64| {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(seconds);
+            env
+        };
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            staking_denom: Some("earth".to_string()),
+            reward_denoms: Some(vec!["fire".to_string(), "obol".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), at(0), info, init_msg).unwrap();
+
+        let mint = |deps: &mut OwnedDeps<MockStorage, MockApi, MockQuerier>, token_id: &str, owner: &str, env: Env| {
+            let mint_msg = MintMsg {
+                token_id: token_id.to_string(),
+                owner: owner.to_string(),
+                token_uri: None,
+                extension: Metadata {
+                    native: Some(coins(1000, "earth")),
+                    cw20: None,
+                    locked_until: None,
+                    vesting: None,
+                },
+            };
+            let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+            entry::execute(deps.as_mut(), env, mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+        };
+        let pending = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, token_id: &str, env: Env| {
+            let query_msg = crate::msg::QueryMsg::PendingRewards { token_id: token_id.to_string() };
+            let res: crate::msg::PendingRewardsResponse = from_binary(&entry::query(deps.as_ref(), env, query_msg).unwrap()).unwrap();
+            res
+        };
+        mint(&mut deps, "1", "bob", at(0));
+
+        let fund_msg = crate::msg::ExecuteMsg::FundRewards { denom: "fire".to_string(), duration: 100 };
+        let info = mock_info("treasury", &[coin(1000, "fire"), coin(5, "air")]);
+        let err = entry::execute(deps.as_mut(), at(0), info, fund_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::InvalidRewardDenom { denom: "air".to_string() });
+        entry::execute(deps.as_mut(), at(0), mock_info("treasury", &coins(1000, "fire")), fund_msg).unwrap();
+        assert_eq!(pending(&deps, "1", at(25)).native, coins(250, "fire"));
+
+        // a token staked halfway only shares in the second half of the stream
+        mint(&mut deps, "2", "alice", at(50));
+        assert_eq!(pending(&deps, "1", at(100)).native, coins(750, "fire"));
+        assert_eq!(pending(&deps, "2", at(100)).native, coins(250, "fire"));
+        assert_eq!(pending(&deps, "2", at(500)).native, coins(250, "fire"));
+
+        // cw20 rewards come in through the receive hook, without a duration they are paid at once
+        let cw20_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "treasury".to_string(),
+            amount: Uint128::new(100),
+            msg: to_binary(&crate::msg::ReceiveMsg::FundRewards { duration: 0 }).unwrap(),
+        });
+        entry::execute(deps.as_mut(), at(100), mock_info("obol", &[]), cw20_msg).unwrap();
+        assert_eq!(pending(&deps, "2", at(100)).cw20, vec![Cw20 { contract_address: "obol".to_string(), amount: Uint128::new(50) }]);
+
+        let claim_msg = crate::msg::ExecuteMsg::ClaimRewards { token_ids: vec!["1".to_string()] };
+        let res = entry::execute(deps.as_mut(), at(100), mock_info("bob", &[]), claim_msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send {
+                to_address: "bob".to_string(),
+                amount: coins(750, "fire"),
+            }),
+            SubMsg::new(WasmMsg::Execute {
+                contract_addr: "obol".to_string(),
+                msg: to_binary(&Cw20ExecuteMsg::Transfer { recipient: "bob".to_string(), amount: Uint128::new(50) }).unwrap(),
+                funds: vec![],
+            }),
+        ]);
+
+        // funding a running stream can neither rush it nor drag it out
+        let fund_msg = crate::msg::ExecuteMsg::FundRewards { denom: "fire".to_string(), duration: 100 };
+        entry::execute(deps.as_mut(), at(100), mock_info("treasury", &coins(1000, "fire")), fund_msg).unwrap();
+        let distribute_msg = crate::msg::ExecuteMsg::DistributeRewards {};
+        entry::execute(deps.as_mut(), at(150), mock_info("anyone", &coins(1, "fire")), distribute_msg).unwrap();
+        assert_eq!(pending(&deps, "1", at(150)).native, coins(250, "fire"));
+        let fund_msg = crate::msg::ExecuteMsg::FundRewards { denom: "fire".to_string(), duration: u64::MAX };
+        let err = entry::execute(deps.as_mut(), at(150), mock_info("anyone", &coins(1, "fire")), fund_msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidDuration { duration: u64::MAX });
+        let fund_msg = crate::msg::ExecuteMsg::FundRewards { denom: "fire".to_string(), duration: 1_000_000 };
+        let res = entry::execute(deps.as_mut(), at(150), mock_info("anyone", &coins(1, "fire")), fund_msg).unwrap();
+        assert_eq!(res.events[0].attributes[4], attr("stream_end", at(200).block.time.seconds().to_string()));
+        assert_eq!(pending(&deps, "1", at(200)).native, coins(501, "fire"));
+    }
+
+    #[test]
+    fn delegate_and_undelegate_principal() {
+        let mut deps = funded_dependencies();
+        let validator = |address: &str| Validator {
+            address: address.to_string(),
+            commission: Decimal::percent(5),
+            max_commission: Decimal::percent(10),
+            max_change_rate: Decimal::percent(1),
+        };
+        let validators = [validator("val1"), validator("val2"), validator("val3")];
+        deps.querier.update_staking("earth", &validators, &[]);
+        let at = |seconds: u64| {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(seconds);
+            env
+        };
+
+        let mut init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            staking_denom: Some("earth".to_string()),
+            validators: Some(vec!["val1".to_string(), "hades".to_string()]),
+            unbonding_period: Some(100),
+            ..Default::default()
+        };
+        let err = entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), init_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::UnknownValidator { validator: "hades".to_string() });
+        init_msg.validators = Some(vec!["val1".to_string(), "val2".to_string()]);
+        entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(vec![coin(7, "air"), coin(1001, "earth")]),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        let info = mock_info(CREATOR, &[coin(7, "air"), coin(1001, "earth")]);
+        entry::execute(deps.as_mut(), at(0), info, exec_msg).unwrap();
+
+        let delegate_msg = crate::msg::ExecuteMsg::Delegate { token_id: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), at(0), mock_info("alice", &[]), delegate_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+        let res = entry::execute(deps.as_mut(), at(0), mock_info("bob", &[]), delegate_msg.clone()).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(StakingMsg::Delegate { validator: "val1".to_string(), amount: coin(501, "earth") }),
+            SubMsg::new(StakingMsg::Delegate { validator: "val2".to_string(), amount: coin(500, "earth") }),
+        ]);
+        sync_delegations(&mut deps, &validators);
+        let err = entry::execute(deps.as_mut(), at(0), mock_info("bob", &[]), delegate_msg).unwrap_err();
+        assert_eq!(err, ContractError::NothingToDelegate { token_id: "1".to_string() });
+
+        // delegated principal can't leave the token, the rest of the escrow can
+        let exec_msg = crate::msg::ExecuteMsg::Withdraw { token_id: "1".to_string(), native: coins(1, "earth"), cw20: vec![] };
+        let err = entry::execute(deps.as_mut(), at(0), mock_info("bob", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::PrincipalLocked { token_id: "1".to_string() });
+        let exec_msg = crate::msg::ExecuteMsg::Withdraw { token_id: "1".to_string(), native: coins(7, "air"), cw20: vec![] };
+        entry::execute(deps.as_mut(), at(0), mock_info("bob", &[]), exec_msg).unwrap();
+
+        // dropping a validator moves its delegation to the new set
+        let config_msg = crate::msg::ExecuteMsg::UpdateConfig(crate::msg::ConfigUpdate {
+            validators: Some(vec!["val2".to_string(), "val3".to_string()]),
+            ..Default::default()
+        });
+        let res = entry::execute(deps.as_mut(), at(0), mock_info(CREATOR, &[]), config_msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(StakingMsg::Redelegate {
+                src_validator: "val1".to_string(),
+                dst_validator: "val2".to_string(),
+                amount: coin(251, "earth"),
+            }),
+            SubMsg::new(StakingMsg::Redelegate {
+                src_validator: "val1".to_string(),
+                dst_validator: "val3".to_string(),
+                amount: coin(250, "earth"),
+            }),
+        ]);
+        sync_delegations(&mut deps, &validators);
+
+        let undelegate_msg = crate::msg::ExecuteMsg::Undelegate { token_id: "1".to_string() };
+        let res = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), undelegate_msg.clone()).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(StakingMsg::Undelegate { validator: "val2".to_string(), amount: coin(751, "earth") }),
+            SubMsg::new(StakingMsg::Undelegate { validator: "val3".to_string(), amount: coin(250, "earth") }),
+        ]);
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), undelegate_msg).unwrap_err();
+        assert_eq!(err, ContractError::NothingToUndelegate { token_id: "1".to_string() });
+
+        let query_msg = crate::msg::QueryMsg::Delegation { token_id: "1".to_string() };
+        let res: crate::msg::DelegationResponse = from_binary(&entry::query(deps.as_ref(), at(10), query_msg.clone()).unwrap()).unwrap();
+        assert_eq!(res, crate::msg::DelegationResponse {
+            delegated: Uint128::zero(),
+            unbonding: vec![crate::state::Unbonding {
+                amount: Uint128::new(1001),
+                release_at: cw721::Expiration::AtTime(at(110).block.time),
+            }],
+            liquid: Uint128::zero(),
+            redemption: None,
+            auto_compound: false,
+        });
+        let withdraw_msg = crate::msg::ExecuteMsg::Withdraw { token_id: "1".to_string(), native: coins(1001, "earth"), cw20: vec![] };
+        let err = entry::execute(deps.as_mut(), at(109), mock_info("bob", &[]), withdraw_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::PrincipalLocked { token_id: "1".to_string() });
+
+        // once unbonded the principal is free again
+        let res: crate::msg::DelegationResponse = from_binary(&entry::query(deps.as_ref(), at(110), query_msg).unwrap()).unwrap();
+        assert_eq!(res.liquid, Uint128::new(1001));
+        entry::execute(deps.as_mut(), at(110), mock_info("bob", &[]), withdraw_msg).unwrap();
+    }
+
+    #[test]
+    fn redeem_unbonds_delegated_principal() {
+        let mut deps = funded_dependencies();
+        let validator = Validator {
+            address: "val1".to_string(),
+            commission: Decimal::percent(5),
+            max_commission: Decimal::percent(10),
+            max_change_rate: Decimal::percent(1),
+        };
+        deps.querier.update_staking("earth", std::slice::from_ref(&validator), &[]);
+        let at = |seconds: u64| {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(seconds);
+            env
+        };
+
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            staking_denom: Some("earth".to_string()),
+            validators: Some(vec!["val1".to_string()]),
+            unbonding_period: Some(100),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), init_msg).unwrap();
+        for token_id in ["1", "2"] {
+            let mint_msg = MintMsg {
+                token_id: token_id.to_string(),
+                owner: "bob".to_string(),
+                token_uri: None,
+                extension: Metadata {
+                    native: Some(vec![coin(7, "air"), coin(1000, "earth")]),
+                    cw20: None,
+                    locked_until: None,
+                    vesting: None,
+                },
+            };
+            let info = mock_info(CREATOR, &[coin(7, "air"), coin(1000, "earth")]);
+            entry::execute(deps.as_mut(), at(0), info, crate::msg::ExecuteMsg::Mint(mint_msg)).unwrap();
+            let delegate_msg = crate::msg::ExecuteMsg::Delegate { token_id: token_id.to_string() };
+            entry::execute(deps.as_mut(), at(0), mock_info("bob", &[]), delegate_msg).unwrap();
+            sync_delegations(&mut deps, std::slice::from_ref(&validator));
+        }
+
+        // redeeming a delegated token undelegates it and keeps the token around
+        let redeem_msg = crate::msg::ExecuteMsg::Redeem { token_id: "1".to_string(), recipient: Some("carl".to_string()) };
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("alice", &[]), redeem_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+        let res = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), redeem_msg.clone()).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(StakingMsg::Undelegate { validator: "val1".to_string(), amount: coin(1000, "earth") }),
+        ]);
+        let release_at = cw721::Expiration::AtTime(at(110).block.time);
+        assert_eq!(res.events[0].ty, "angel_unbond");
+        assert!(res.events[0].attributes.contains(&attr("release_at", release_at.to_string())));
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), redeem_msg).unwrap_err();
+        assert_eq!(err, ContractError::TokenRedeeming { token_id: "1".to_string() });
+
+        let query_msg = crate::msg::QueryMsg::Delegation { token_id: "1".to_string() };
+        let res: crate::msg::DelegationResponse = from_binary(&entry::query(deps.as_ref(), at(10), query_msg).unwrap()).unwrap();
+        assert_eq!(res.redemption, Some(crate::state::Redemption { recipient: Some(Addr::unchecked("carl")), release_at }));
+
+        // the escrow of a redeeming token is frozen
+        let withdraw_msg = crate::msg::ExecuteMsg::Withdraw { token_id: "1".to_string(), native: coins(7, "air"), cw20: vec![] };
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), withdraw_msg).unwrap_err();
+        assert_eq!(err, ContractError::TokenRedeeming { token_id: "1".to_string() });
+        let deposit_msg = crate::msg::ExecuteMsg::Deposit { token_id: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("bob", &coins(1, "air")), deposit_msg).unwrap_err();
+        assert_eq!(err, ContractError::TokenRedeeming { token_id: "1".to_string() });
+
+        // transfers are locked by default and can be allowed by the minter
+        let transfer_msg = crate::msg::ExecuteMsg::TransferNft { recipient: "alice".to_string(), token_id: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), transfer_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::TokenRedeeming { token_id: "1".to_string() });
+        let config_msg = crate::msg::ExecuteMsg::UpdateConfig(crate::msg::ConfigUpdate {
+            unbonding_transfers: Some(crate::state::TransferPolicy::Allowed),
+            ..Default::default()
+        });
+        entry::execute(deps.as_mut(), at(10), mock_info(CREATOR, &[]), config_msg).unwrap();
+        entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), transfer_msg).unwrap();
+
+        // claiming waits for the unbonding to finish, then anyone can pay the recipient
+        let claim_msg = crate::msg::ExecuteMsg::ClaimUnbonded { token_id: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), at(109), mock_info("dave", &[]), claim_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::StillUnbonding { token_id: "1".to_string(), release_at });
+        let res = entry::execute(deps.as_mut(), at(110), mock_info("dave", &[]), claim_msg.clone()).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(BankMsg::Send {
+            to_address: "carl".to_string(),
+            amount: vec![coin(7, "air"), coin(1000, "earth")],
+        })]);
+        let err = entry::execute(deps.as_mut(), at(110), mock_info("dave", &[]), claim_msg).unwrap_err();
+        assert_eq!(err, ContractError::TokenNotFound { token_id: "1".to_string() });
+
+        // a burned token pays whoever owns it when claimed
+        let burn_msg = crate::msg::ExecuteMsg::Burn { token_id: "2".to_string() };
+        entry::execute(deps.as_mut(), at(110), mock_info("bob", &[]), burn_msg).unwrap();
+        let transfer_msg = crate::msg::ExecuteMsg::TransferNft { recipient: "alice".to_string(), token_id: "2".to_string() };
+        entry::execute(deps.as_mut(), at(110), mock_info("bob", &[]), transfer_msg).unwrap();
+        let claim_msg = crate::msg::ExecuteMsg::ClaimUnbonded { token_id: "2".to_string() };
+        let res = entry::execute(deps.as_mut(), at(210), mock_info("dave", &[]), claim_msg).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(BankMsg::Send {
+            to_address: "alice".to_string(),
+            amount: vec![coin(7, "air"), coin(1000, "earth")],
+        })]);
+    }
+
+    #[test]
+    fn unbonding_batches_and_slashes() {
+        let mut deps = funded_dependencies();
+        let validator = Validator {
+            address: "val1".to_string(),
+            commission: Decimal::percent(5),
+            max_commission: Decimal::percent(10),
+            max_change_rate: Decimal::percent(1),
+        };
+        deps.querier.update_staking("earth", std::slice::from_ref(&validator), &[]);
+        let at = |seconds: u64| {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(seconds);
+            env
+        };
+
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            staking_denom: Some("earth".to_string()),
+            validators: Some(vec!["val1".to_string()]),
+            unbonding_period: Some(60),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), init_msg).unwrap();
+        for (token_id, amount) in [("1", 1000), ("2", 3000), ("3", 1000)] {
+            let mint_msg = MintMsg {
+                token_id: token_id.to_string(),
+                owner: "bob".to_string(),
+                token_uri: None,
+                extension: Metadata { native: Some(coins(amount, "earth")), cw20: None, locked_until: None, vesting: None },
+            };
+            let info = mock_info(CREATOR, &coins(amount, "earth"));
+            entry::execute(deps.as_mut(), at(0), info, crate::msg::ExecuteMsg::Mint(mint_msg)).unwrap();
+            let delegate_msg = crate::msg::ExecuteMsg::Delegate { token_id: token_id.to_string() };
+            entry::execute(deps.as_mut(), at(0), mock_info("bob", &[]), delegate_msg).unwrap();
+            sync_delegations(&mut deps, std::slice::from_ref(&validator));
+        }
+
+        // the first undelegation goes out at once, the next ones wait for the epoch to pass
+        let undelegate_msg = crate::msg::ExecuteMsg::Undelegate { token_id: "1".to_string() };
+        let res = entry::execute(deps.as_mut(), at(0), mock_info("bob", &[]), undelegate_msg).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(StakingMsg::Undelegate { validator: "val1".to_string(), amount: coin(1000, "earth") })]);
+        sync_delegations(&mut deps, std::slice::from_ref(&validator));
+        let redeem_msg = crate::msg::ExecuteMsg::Redeem { token_id: "2".to_string(), recipient: None };
+        let res = entry::execute(deps.as_mut(), at(5), mock_info("bob", &[]), redeem_msg).unwrap();
+        assert_eq!(res.messages, vec![]);
+        let query_msg = crate::msg::QueryMsg::Delegation { token_id: "2".to_string() };
+        let res: crate::msg::DelegationResponse = from_binary(&entry::query(deps.as_ref(), at(5), query_msg.clone()).unwrap()).unwrap();
+        assert_eq!(res.unbonding, vec![crate::state::Unbonding { amount: Uint128::new(3000), release_at: cw721::Expiration::Never {} }]);
+        let err = entry::execute(deps.as_mut(), at(9), mock_info("dave", &[]), crate::msg::ExecuteMsg::Unbond {}).unwrap_err();
+        assert_eq!(err, ContractError::NoBatchDue {});
+
+        // a slash of the validator comes out of the tokens still bonded to it
+        let delegation = FullDelegation {
+            delegator: Addr::unchecked(MOCK_CONTRACT_ADDR),
+            validator: "val1".to_string(),
+            amount: coin(3600, "earth"),
+            can_redelegate: coin(3600, "earth"),
+            accumulated_rewards: vec![],
+        };
+        deps.querier.update_staking("earth", std::slice::from_ref(&validator), &[delegation]);
+        let res = entry::execute(deps.as_mut(), at(10), mock_info("dave", &[]), crate::msg::ExecuteMsg::Unbond {}).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(StakingMsg::Undelegate { validator: "val1".to_string(), amount: coin(2700, "earth") })]);
+        assert_eq!(res.events.iter().filter(|event| event.ty == "angel_slash").count(), 2);
+
+        let res: crate::msg::DelegationResponse = from_binary(&entry::query(deps.as_ref(), at(10), query_msg).unwrap()).unwrap();
+        let release_at = cw721::Expiration::AtTime(at(70).block.time);
+        assert_eq!(res.unbonding, vec![crate::state::Unbonding { amount: Uint128::new(2700), release_at }]);
+        assert_eq!(res.redemption, Some(crate::state::Redemption { recipient: None, release_at }));
+        let escrow_of = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, token_id: &str| {
+            let query_msg = crate::msg::QueryMsg::NftInfo { token_id: token_id.to_string() };
+            let res: NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), at(10), query_msg).unwrap()).unwrap();
+            res.extension.native
+        };
+        assert_eq!(escrow_of(&deps, "1"), Some(coins(1000, "earth")));
+        assert_eq!(escrow_of(&deps, "2"), Some(coins(2700, "earth")));
+
+        // tokens not touched since apply the slash the next time they are
+        assert_eq!(escrow_of(&deps, "3"), Some(coins(1000, "earth")));
+        let query_msg = crate::msg::QueryMsg::Delegation { token_id: "3".to_string() };
+        let res: crate::msg::DelegationResponse = from_binary(&entry::query(deps.as_ref(), at(10), query_msg).unwrap()).unwrap();
+        assert_eq!((res.delegated, res.liquid), (Uint128::new(900), Uint128::zero()));
+        let undelegate_msg = crate::msg::ExecuteMsg::Undelegate { token_id: "3".to_string() };
+        let res = entry::execute(deps.as_mut(), at(11), mock_info("bob", &[]), undelegate_msg).unwrap();
+        assert_eq!(res.events.iter().filter(|event| event.ty == "angel_slash").count(), 1);
+        assert_eq!(escrow_of(&deps, "3"), Some(coins(900, "earth")));
+        let batch = crate::state::UNBONDING_BATCH.load(&deps.storage).unwrap();
+        assert_eq!(batch.amount, Uint128::new(900));
+    }
+
+    #[test]
+    fn compound_delegator_rewards() {
+        let mut deps = funded_dependencies();
+        let validator = Validator {
+            address: "val1".to_string(),
+            commission: Decimal::percent(5),
+            max_commission: Decimal::percent(10),
+            max_change_rate: Decimal::percent(1),
+        };
+        deps.querier.update_staking("earth", std::slice::from_ref(&validator), &[]);
+
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            staking_denom: Some("earth".to_string()),
+            validators: Some(vec!["val1".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), init_msg).unwrap();
+        for (token_id, amount) in [("1", 1000), ("2", 2000)] {
+            let mint_msg = MintMsg {
+                token_id: token_id.to_string(),
+                owner: "bob".to_string(),
+                token_uri: None,
+                extension: Metadata {
+                    native: Some(coins(amount, "earth")),
+                    cw20: None,
+                    locked_until: None,
+                    vesting: None,
+                },
+            };
+            let info = mock_info(CREATOR, &coins(amount, "earth"));
+            entry::execute(deps.as_mut(), mock_env(), info, crate::msg::ExecuteMsg::Mint(mint_msg)).unwrap();
+            let delegate_msg = crate::msg::ExecuteMsg::Delegate { token_id: token_id.to_string() };
+            entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), delegate_msg).unwrap();
+            sync_delegations(&mut deps, std::slice::from_ref(&validator));
+        }
+
+        let compound_msg = crate::msg::ExecuteMsg::Compound { start_after: None, limit: None };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("dave", &[]), compound_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::NothingToCompound {});
+
+        // rewards earned in a later block are withdrawn and shared by the bonded principal
+        let mut env = mock_env();
+        env.block.height += 1;
+        sync_delegations_with_rewards(&mut deps, std::slice::from_ref(&validator), &[coin(30, "earth"), coin(6, "fire")]);
+
+        // only token 1 opts in, token 2 follows the collection and keeps its share to claim
+        let exec_msg = crate::msg::ExecuteMsg::SetAutoCompound { token_id: "1".to_string(), auto_compound: Some(true) };
+        let err = entry::execute(deps.as_mut(), env.clone(), mock_info("alice", &[]), exec_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+        entry::execute(deps.as_mut(), env.clone(), mock_info("bob", &[]), exec_msg).unwrap();
+
+        // a page of one token restakes token 1, token 2 has its share to claim either way
+        let page_msg = crate::msg::ExecuteMsg::Compound { start_after: None, limit: Some(1) };
+        let res = entry::execute(deps.as_mut(), env.clone(), mock_info("dave", &[]), page_msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(DistributionMsg::WithdrawDelegatorReward { validator: "val1".to_string() }),
+            SubMsg::new(StakingMsg::Delegate { validator: "val1".to_string(), amount: coin(10, "earth") }),
+        ]);
+        assert!(res.attributes.contains(&attr("last_token_id", "1")));
+        sync_delegations(&mut deps, std::slice::from_ref(&validator));
+        let query_msg = crate::msg::QueryMsg::PendingRewards { token_id: "2".to_string() };
+        let res: crate::msg::PendingRewardsResponse = from_binary(&entry::query(deps.as_ref(), env.clone(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.native, vec![coin(20, "earth"), coin(4, "fire")]);
+        let page_msg = crate::msg::ExecuteMsg::Compound { start_after: Some("1".to_string()), limit: None };
+        let res = entry::execute(deps.as_mut(), env.clone(), mock_info("dave", &[]), page_msg).unwrap();
+        assert_eq!(res.messages, vec![]);
+        assert!(res.attributes.contains(&attr("last_token_id", "2")));
+
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1".to_string() };
+        let res: NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), env.clone(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.extension.native, Some(vec![coin(1010, "earth"), coin(2, "fire")]));
+        let query_msg = crate::msg::QueryMsg::Delegation { token_id: "1".to_string() };
+        let res: crate::msg::DelegationResponse = from_binary(&entry::query(deps.as_ref(), env.clone(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.delegated, Uint128::new(1010));
+        assert!(res.auto_compound);
+
+        let query_msg = crate::msg::QueryMsg::PendingRewards { token_id: "2".to_string() };
+        let res: crate::msg::PendingRewardsResponse = from_binary(&entry::query(deps.as_ref(), env.clone(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.native, vec![coin(20, "earth"), coin(4, "fire")]);
+
+        // delegating withdraws the pending rewards first, so the late delegator gets no
+        // share of them and none of them goes unaccounted
+        env.block.height += 1;
+        sync_delegations_with_rewards(&mut deps, std::slice::from_ref(&validator), &[coin(30, "earth"), coin(6, "fire")]);
+        let mint_msg = MintMsg {
+            token_id: "3".to_string(),
+            owner: "carl".to_string(),
+            token_uri: None,
+            extension: Metadata { native: Some(coins(3000, "earth")), cw20: None, locked_until: None, vesting: None },
+        };
+        let info = mock_info(CREATOR, &coins(3000, "earth"));
+        entry::execute(deps.as_mut(), env.clone(), info, crate::msg::ExecuteMsg::Mint(mint_msg)).unwrap();
+        let delegate_msg = crate::msg::ExecuteMsg::Delegate { token_id: "3".to_string() };
+        let res = entry::execute(deps.as_mut(), env.clone(), mock_info("carl", &[]), delegate_msg).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(DistributionMsg::WithdrawDelegatorReward { validator: "val1".to_string() }),
+            SubMsg::new(StakingMsg::Delegate { validator: "val1".to_string(), amount: coin(3000, "earth") }),
+        ]);
+
+        env.block.height += 1;
+        sync_delegations(&mut deps, std::slice::from_ref(&validator));
+        let res = entry::execute(deps.as_mut(), env.clone(), mock_info("dave", &[]), compound_msg.clone()).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(StakingMsg::Delegate {
+            validator: "val1".to_string(),
+            amount: coin(10, "earth"),
+        })]);
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1".to_string() };
+        let res: NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), env.clone(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.extension.native, Some(vec![coin(1020, "earth"), coin(4, "fire")]));
+        let query_msg = crate::msg::QueryMsg::PendingRewards { token_id: "3".to_string() };
+        let res: crate::msg::PendingRewardsResponse = from_binary(&entry::query(deps.as_ref(), env.clone(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.native, vec![]);
+        let err = entry::execute(deps.as_mut(), env.clone(), mock_info("dave", &[]), compound_msg).unwrap_err();
+        assert_eq!(err, ContractError::NothingToCompound {});
+
+        let claim_msg = crate::msg::ExecuteMsg::ClaimRewards { token_ids: vec!["2".to_string()] };
+        let res = entry::execute(deps.as_mut(), env, mock_info("bob", &[]), claim_msg).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(BankMsg::Send {
+            to_address: "bob".to_string(),
+            amount: vec![coin(39, "earth"), coin(7, "fire")],
+        })]);
+    }
+
+    #[test]
+    fn merge_tokens() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        for (token_id, owner, funds) in [
+            ("1", "bob", vec![coin(100, "earth")]),
+            ("2", "bob", vec![coin(200, "earth"), coin(5, "fire")]),
+            ("3", "bob", vec![coin(300, "earth")]),
+            ("4", "alice", vec![coin(400, "earth")]),
+        ] {
+            let mint_msg = MintMsg {
+                token_id: token_id.to_string(),
+                owner: owner.to_string(),
+                token_uri: None,
+                extension: Metadata {
+                    native: Some(funds.clone()),
+                    cw20: None,
+                    locked_until: None,
+                    vesting: None,
+                },
+            };
+            let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+            entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &funds), exec_msg).unwrap();
+        }
+
+        let exec_msg = crate::msg::ExecuteMsg::Merge { token_ids: vec!["2".to_string(), "1".to_string()], into: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::DuplicateTokenId { token_id: "1".to_string() });
+
+        // alice's token can't be pulled in, even if she approved bob
+        let approve_msg = crate::msg::ExecuteMsg::Approve { spender: "bob".to_string(), token_id: "4".to_string(), expires: None };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), approve_msg).unwrap();
+        let exec_msg = crate::msg::ExecuteMsg::Merge { token_ids: vec!["4".to_string()], into: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::OwnerMismatch { token_id: "4".to_string() });
+
+        // a locked token can't be merged away, whatever lock-up the target has
+        let locked_until = Expiration::AtHeight(mock_env().block.height + 100);
+        let mint_msg = MintMsg {
+            token_id: "5".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata { native: Some(coins(500, "earth")), cw20: None, locked_until: Some(locked_until), vesting: None },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(500, "earth")), exec_msg).unwrap();
+        let exec_msg = crate::msg::ExecuteMsg::Merge { token_ids: vec!["5".to_string()], into: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::TokenLocked { token_id: "5".to_string(), locked_until });
+
+        let exec_msg = crate::msg::ExecuteMsg::Merge { token_ids: vec!["2".to_string(), "3".to_string()], into: "1".to_string() };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1".to_string() };
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.extension.native, Some(vec![coin(600, "earth"), coin(5, "fire")]));
+
+        let query_msg = crate::msg::QueryMsg::NumTokens {};
+        let res : cw721::NumTokensResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.count, 3);
+    }
+
+    #[test]
+    fn split_token() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: Some("json".to_string()),
+            extension: Metadata {
+                native: Some(vec![coin(1000, "earth"), coin(10, "fire")]),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        let info = mock_info(CREATOR, &[coin(1000, "earth"), coin(10, "fire")]);
+        entry::execute(deps.as_mut(), mock_env(), info, exec_msg).unwrap();
+
+        let part = |native: Vec<Coin>| Metadata { native: Some(native), cw20: None, locked_until: None, vesting: None };
+
+        // parts have to add up to the escrow exactly
+        let exec_msg = crate::msg::ExecuteMsg::Split {
+            token_id: "1".to_string(),
+            parts: vec![part(coins(600, "earth")), part(vec![coin(300, "earth"), coin(10, "fire")])],
+        };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::SplitMismatch { token_id: "1".to_string() });
+
+        // every part has to hold something, and there is a limit to how many there are
+        let exec_msg = crate::msg::ExecuteMsg::Split {
+            token_id: "1".to_string(),
+            parts: vec![part(coins(1000, "earth")), part(coins(10, "fire")), part(vec![])],
+        };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::EmptyPart {});
+        let exec_msg = crate::msg::ExecuteMsg::Split { token_id: "1".to_string(), parts: vec![part(coins(1, "fire")); 11] };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::TooManyParts { max: 10 });
+
+        let exec_msg = crate::msg::ExecuteMsg::Split {
+            token_id: "1".to_string(),
+            parts: vec![part(coins(600, "earth")), part(vec![coin(400, "earth"), coin(10, "fire")])],
+        };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("alice", &[]), exec_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::Tokens { owner: "bob".to_string(), start_after: None, limit: None };
+        let res : cw721::TokensResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.tokens, vec!["1-1".to_string(), "1-2".to_string()]);
+
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1-2".to_string() };
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.token_uri, Some("json".to_string()));
+        assert_eq!(res.extension, part(vec![coin(400, "earth"), coin(10, "fire")]));
+    }
+
+    #[test]
+    fn locked_tokens_keep_their_escrow() {
+        let mut deps = funded_dependencies();
+        let at = |seconds: u64| {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(seconds);
+            env
+        };
+
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            locked_transfers: Some(crate::state::TransferPolicy::Locked),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), init_msg).unwrap();
+        let locked_until = cw721::Expiration::AtTime(at(100).block.time);
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(10, "air")),
+                cw20: None,
+                locked_until: Some(locked_until),
+                vesting: None,
+            },
+        };
+        let info = mock_info(CREATOR, &coins(10, "air"));
+        entry::execute(deps.as_mut(), at(0), info, crate::msg::ExecuteMsg::Mint(mint_msg)).unwrap();
+
+        let locked = ContractError::TokenLocked { token_id: "1".to_string(), locked_until };
+        let redeem_msg = crate::msg::ExecuteMsg::Redeem { token_id: "1".to_string(), recipient: None };
+        let err = entry::execute(deps.as_mut(), at(99), mock_info("bob", &[]), redeem_msg).unwrap_err();
+        assert_eq!(err, locked);
+        let burn_msg = crate::msg::ExecuteMsg::Burn { token_id: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), at(99), mock_info("bob", &[]), burn_msg.clone()).unwrap_err();
+        assert_eq!(err, locked);
+        let withdraw_msg = crate::msg::ExecuteMsg::Withdraw { token_id: "1".to_string(), native: coins(1, "air"), cw20: vec![] };
+        let err = entry::execute(deps.as_mut(), at(99), mock_info("bob", &[]), withdraw_msg).unwrap_err();
+        assert_eq!(err, locked);
+        let transfer_msg = crate::msg::ExecuteMsg::TransferNft { recipient: "alice".to_string(), token_id: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), at(99), mock_info("bob", &[]), transfer_msg.clone()).unwrap_err();
+        assert_eq!(err, locked);
+
+        // deposits are still welcome and splitting doesn't shake the lock off
+        let deposit_msg = crate::msg::ExecuteMsg::Deposit { token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), at(99), mock_info("bob", &coins(10, "air")), deposit_msg).unwrap();
+        let split_msg = crate::msg::ExecuteMsg::Split {
+            token_id: "1".to_string(),
+            parts: vec![
+                Metadata { native: Some(coins(15, "air")), cw20: None, locked_until: None, vesting: None },
+                Metadata { native: Some(coins(5, "air")), cw20: None, locked_until: None, vesting: None },
+            ],
+        };
+        entry::execute(deps.as_mut(), at(99), mock_info("bob", &[]), split_msg).unwrap();
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1-2".to_string() };
+        let res: NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), at(99), query_msg).unwrap()).unwrap();
+        assert_eq!(res.extension.locked_until, Some(locked_until));
+
+        // once the lock-up is over the token behaves as usual
+        let transfer_msg = crate::msg::ExecuteMsg::TransferNft { recipient: "alice".to_string(), token_id: "1-2".to_string() };
+        entry::execute(deps.as_mut(), at(100), mock_info("bob", &[]), transfer_msg).unwrap();
+        let burn_msg = crate::msg::ExecuteMsg::Burn { token_id: "1-2".to_string() };
+        let res = entry::execute(deps.as_mut(), at(100), mock_info("alice", &[]), burn_msg).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(BankMsg::Send { to_address: "alice".to_string(), amount: coins(5, "air") })]);
+    }
+
+    #[test]
+    fn exit_early_forfeits_penalty() {
+        let mut deps = funded_dependencies();
+        let at = |seconds: u64| {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(seconds);
+            env
+        };
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            staking_denom: Some("earth".to_string()),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), init_msg).unwrap();
+        let locked_until = Some(cw721::Expiration::AtTime(at(100).block.time));
+        for (token_id, owner, native, locked_until) in [
+            ("1", "bob", vec![coin(50, "air"), coin(1000, "earth")], locked_until),
+            ("2", "alice", coins(1000, "earth"), None),
+            ("3", "bob", coins(1000, "earth"), locked_until),
+        ] {
+            let mint_msg = MintMsg {
+                token_id: token_id.to_string(),
+                owner: owner.to_string(),
+                token_uri: None,
+                extension: Metadata { native: Some(native.clone()), cw20: None, locked_until, vesting: None },
+            };
+            let info = mock_info(CREATOR, &native);
+            entry::execute(deps.as_mut(), at(0), info, crate::msg::ExecuteMsg::Mint(mint_msg)).unwrap();
+        }
+
+        let exit = |token_id: &str| crate::msg::ExecuteMsg::ExitEarly { token_id: token_id.to_string(), recipient: None };
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), exit("1")).unwrap_err();
+        assert_eq!(err, ContractError::EarlyExitDisabled {});
+
+        let update = |penalty: Decimal, sink: crate::state::PenaltySink| crate::msg::ExecuteMsg::UpdateConfig(crate::msg::ConfigUpdate {
+            early_exit: Some(crate::state::EarlyExit { penalty, sink }),
+            ..Default::default()
+        });
+        let treasury = crate::state::PenaltySink::Treasury { address: "treasury".to_string() };
+        let err = entry::execute(deps.as_mut(), at(10), mock_info(CREATOR, &[]), update(Decimal::percent(150), treasury.clone())).unwrap_err();
+        assert_eq!(err, ContractError::InvalidPenalty {});
+        entry::execute(deps.as_mut(), at(10), mock_info(CREATOR, &[]), update(Decimal::percent(10), treasury)).unwrap();
+
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("alice", &[]), exit("2")).unwrap_err();
+        assert_eq!(err, ContractError::NotLocked { token_id: "2".to_string() });
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("alice", &[]), exit("1")).unwrap_err();
+        assert_eq!(err, ContractError::Base(cw721_base::ContractError::Unauthorized {}));
+
+        // the penalty goes to the treasury, the rest to the owner
+        let res = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), exit("1")).unwrap();
+        assert_eq!(res.messages, vec![
+            SubMsg::new(BankMsg::Send { to_address: "bob".to_string(), amount: vec![coin(45, "air"), coin(900, "earth")] }),
+            SubMsg::new(BankMsg::Send { to_address: "treasury".to_string(), amount: vec![coin(5, "air"), coin(100, "earth")] }),
+        ]);
+
+        // or to the tokens that stay
+        let stakers = crate::state::PenaltySink::Stakers;
+        entry::execute(deps.as_mut(), at(10), mock_info(CREATOR, &[]), update(Decimal::percent(50), stakers)).unwrap();
+        let res = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), exit("3")).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(BankMsg::Send { to_address: "bob".to_string(), amount: coins(500, "earth") })]);
+        let query_msg = crate::msg::QueryMsg::PendingRewards { token_id: "2".to_string() };
+        let res: crate::msg::PendingRewardsResponse = from_binary(&entry::query(deps.as_ref(), at(10), query_msg).unwrap()).unwrap();
+        assert_eq!(res.native, coins(500, "earth"));
+
+        // early exits can be turned off again
+        let mint_msg = MintMsg {
+            token_id: "4".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata { native: Some(coins(1000, "earth")), cw20: None, locked_until, vesting: None },
+        };
+        let info = mock_info(CREATOR, &coins(1000, "earth"));
+        entry::execute(deps.as_mut(), at(10), info, crate::msg::ExecuteMsg::Mint(mint_msg)).unwrap();
+        let disable = crate::msg::ConfigUpdate { disable_early_exit: Some(true), ..Default::default() };
+        let conflict = crate::msg::ConfigUpdate {
+            early_exit: Some(crate::state::EarlyExit { penalty: Decimal::percent(10), sink: crate::state::PenaltySink::Stakers }),
+            ..disable.clone()
+        };
+        let err = entry::execute(deps.as_mut(), at(10), mock_info(CREATOR, &[]), crate::msg::ExecuteMsg::UpdateConfig(conflict)).unwrap_err();
+        assert_eq!(err, ContractError::EarlyExitConflict {});
+        entry::execute(deps.as_mut(), at(10), mock_info(CREATOR, &[]), crate::msg::ExecuteMsg::UpdateConfig(disable)).unwrap();
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), exit("4")).unwrap_err();
+        assert_eq!(err, ContractError::EarlyExitDisabled {});
+
+        // a collection that stakes nothing has no stakers to pay
+        let mut deps = funded_dependencies();
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            early_exit: Some(crate::state::EarlyExit { penalty: Decimal::percent(10), sink: crate::state::PenaltySink::Stakers }),
+            ..Default::default()
+        };
+        let err = entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), init_msg).unwrap_err();
+        assert_eq!(err, ContractError::NoStakers {});
+    }
+
+    #[test]
+    fn vesting_tokens_release_vested_assets() {
+        let mut deps = funded_dependencies();
+        let at = |seconds: u64| {
+            let mut env = mock_env();
+            env.block.time = env.block.time.plus_seconds(seconds);
+            env
+        };
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), init_msg).unwrap();
+
+        let mut vesting = Vesting { start: at(0).block.time, cliff: at(120).block.time, end: at(100).block.time };
+        let mint = |vesting: &Vesting| crate::msg::ExecuteMsg::Mint(MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "air")),
+                cw20: None,
+                locked_until: None,
+                vesting: Some(vesting.clone()),
+            },
+        });
+        let info = mock_info(CREATOR, &coins(1000, "air"));
+        let err = entry::execute(deps.as_mut(), at(0), info.clone(), mint(&vesting)).unwrap_err();
+        assert_eq!(err, ContractError::InvalidVesting {});
+        vesting.cliff = at(25).block.time;
+        entry::execute(deps.as_mut(), at(0), info, mint(&vesting)).unwrap();
+
+        // nothing leaves before the cliff
+        let withdraw = |amount: u128| crate::msg::ExecuteMsg::Withdraw { token_id: "1".to_string(), native: coins(amount, "air"), cw20: vec![] };
+        let err = entry::execute(deps.as_mut(), at(10), mock_info("bob", &[]), withdraw(1)).unwrap_err();
+        assert_eq!(err, ContractError::Unvested { token_id: "1".to_string() });
+        let query_msg = crate::msg::QueryMsg::Vested { token_id: "1".to_string() };
+        let res: crate::msg::VestedResponse = from_binary(&entry::query(deps.as_ref(), at(10), query_msg.clone()).unwrap()).unwrap();
+        assert_eq!(res.vested_native, vec![]);
+
+        // withdrawals count as vested, only the rest of the vested part can follow
+        entry::execute(deps.as_mut(), at(50), mock_info("bob", &[]), withdraw(400)).unwrap();
+        let err = entry::execute(deps.as_mut(), at(50), mock_info("bob", &[]), withdraw(101)).unwrap_err();
+        assert_eq!(err, ContractError::Unvested { token_id: "1".to_string() });
+        let res: crate::msg::VestedResponse = from_binary(&entry::query(deps.as_ref(), at(75), query_msg).unwrap()).unwrap();
+        assert_eq!(res, crate::msg::VestedResponse {
+            vested_native: coins(750, "air"),
+            vested_cw20: vec![],
+            withdrawable_native: coins(350, "air"),
+            withdrawable_cw20: vec![],
+        });
+
+        // the token can change hands while vesting, and be burned once it is over
+        let burn_msg = crate::msg::ExecuteMsg::Burn { token_id: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), at(75), mock_info("bob", &[]), burn_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unvested { token_id: "1".to_string() });
+        let transfer_msg = crate::msg::ExecuteMsg::TransferNft { recipient: "alice".to_string(), token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), at(75), mock_info("bob", &[]), transfer_msg).unwrap();
+
+        // nothing is merged in before the vesting is over, it would vest along
+        let mint_msg = crate::msg::ExecuteMsg::Mint(MintMsg {
+            token_id: "2".to_string(),
+            owner: "alice".to_string(),
+            token_uri: None,
+            extension: Metadata { native: Some(coins(100, "air")), cw20: None, locked_until: None, vesting: None },
+        });
+        entry::execute(deps.as_mut(), at(75), mock_info(CREATOR, &coins(100, "air")), mint_msg).unwrap();
+        let merge_msg = crate::msg::ExecuteMsg::Merge { token_ids: vec!["2".to_string()], into: "1".to_string() };
+        let err = entry::execute(deps.as_mut(), at(75), mock_info("alice", &[]), merge_msg.clone()).unwrap_err();
+        assert_eq!(err, ContractError::Unvested { token_id: "1".to_string() });
+        entry::execute(deps.as_mut(), at(100), mock_info("alice", &[]), merge_msg).unwrap();
+
+        let res = entry::execute(deps.as_mut(), at(100), mock_info("alice", &[]), burn_msg).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(BankMsg::Send { to_address: "alice".to_string(), amount: coins(700, "air") })]);
+    }
+
+    #[test]
+    fn voting_power_follows_escrow_and_owner() {
+        let mut deps = funded_dependencies();
+        let at = |height: u64| {
+            let mut env = mock_env();
+            env.block.height += height;
+            env
+        };
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            voting_weights: Some(vec![
+                crate::state::VotingWeight { asset: "earth".to_string(), weight: Decimal::one() },
+                crate::state::VotingWeight { asset: "air".to_string(), weight: Decimal::percent(50) },
+            ]),
+            ..Default::default()
+        };
+        let mut duplicate_msg = init_msg.clone();
+        duplicate_msg.voting_weights.as_mut().unwrap().push(crate::state::VotingWeight { asset: "air".to_string(), weight: Decimal::one() });
+        let err = entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), duplicate_msg).unwrap_err();
+        assert_eq!(err, ContractError::DuplicateVotingWeight { asset: "air".to_string() });
+
+        // a weight too large for the escrow fails the mint instead of panicking
+        let mut overflow_deps = funded_dependencies();
+        let mut overflow_msg = init_msg.clone();
+        overflow_msg.voting_weights = Some(vec![crate::state::VotingWeight { asset: "earth".to_string(), weight: Decimal::MAX }]);
+        entry::instantiate(overflow_deps.as_mut(), at(0), mock_info(CREATOR, &[]), overflow_msg).unwrap();
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata { native: Some(coins(u128::MAX, "earth")), cw20: None, locked_until: None, vesting: None },
+        };
+        let info = mock_info(CREATOR, &coins(u128::MAX, "earth"));
+        let err = entry::execute(overflow_deps.as_mut(), at(0), info, crate::msg::ExecuteMsg::Mint(mint_msg)).unwrap_err();
+        assert!(matches!(err, ContractError::Std(cosmwasm_std::StdError::Overflow { .. })));
+
+        entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), init_msg).unwrap();
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(vec![coin(10, "air"), coin(1000, "earth"), coin(7, "fire")]),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let info = mock_info(CREATOR, &[coin(10, "air"), coin(1000, "earth"), coin(7, "fire")]);
+        entry::execute(deps.as_mut(), at(0), info, crate::msg::ExecuteMsg::Mint(mint_msg)).unwrap();
+        let deposit_msg = crate::msg::ExecuteMsg::Deposit { token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), at(1), mock_info("alice", &coins(100, "earth")), deposit_msg).unwrap();
+        let transfer_msg = crate::msg::ExecuteMsg::TransferNft { recipient: "alice".to_string(), token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), at(2), mock_info("bob", &[]), transfer_msg).unwrap();
+        let burn_msg = crate::msg::ExecuteMsg::Burn { token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), at(3), mock_info("alice", &[]), burn_msg).unwrap();
+
+        // changes count from the block after they happen
+        let power = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, address: &str, height: u64| {
+            let query_msg = crate::msg::QueryMsg::VotingPowerAtHeight { address: address.to_string(), height: Some(at(height).block.height) };
+            let res: crate::msg::VotingPowerAtHeightResponse = from_binary(&entry::query(deps.as_ref(), at(4), query_msg).unwrap()).unwrap();
+            res.power.u128()
+        };
+        assert_eq!([0, 1, 2, 3, 4].map(|height| power(&deps, "bob", height)), [0, 1005, 1105, 0, 0]);
+        assert_eq!([0, 1, 2, 3, 4].map(|height| power(&deps, "alice", height)), [0, 0, 0, 1105, 0]);
+
+        let query_msg = crate::msg::QueryMsg::TotalPowerAtHeight { height: None };
+        let res: crate::msg::TotalPowerAtHeightResponse = from_binary(&entry::query(deps.as_ref(), at(3), query_msg).unwrap()).unwrap();
+        assert_eq!(res, crate::msg::TotalPowerAtHeightResponse { power: Uint128::new(1105), height: at(3).block.height });
+    }
+
+    #[test]
+    fn owners_and_balances_by_height() {
+        let mut deps = funded_dependencies();
+        let at = |height: u64| {
+            let mut env = mock_env();
+            env.block.height += height;
+            env
+        };
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), at(0), mock_info(CREATOR, &[]), init_msg).unwrap();
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata { native: Some(coins(1000, "earth")), cw20: None, locked_until: None, vesting: None },
+        };
+        entry::execute(deps.as_mut(), at(0), mock_info(CREATOR, &coins(1000, "earth")), crate::msg::ExecuteMsg::Mint(mint_msg)).unwrap();
+        let deposit_msg = crate::msg::ExecuteMsg::Deposit { token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), at(1), mock_info("alice", &coins(100, "earth")), deposit_msg).unwrap();
+        let transfer_msg = crate::msg::ExecuteMsg::TransferNft { recipient: "alice".to_string(), token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), at(2), mock_info("bob", &[]), transfer_msg).unwrap();
+        let burn_msg = crate::msg::ExecuteMsg::Burn { token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), at(3), mock_info("alice", &[]), burn_msg).unwrap();
+
+        // changes count from the block after they happen
+        let owner = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, height: u64| {
+            let query_msg = crate::msg::QueryMsg::OwnerOfAtHeight { token_id: "1".to_string(), height: Some(at(height).block.height) };
+            let res: crate::msg::OwnerOfAtHeightResponse = from_binary(&entry::query(deps.as_ref(), at(4), query_msg).unwrap()).unwrap();
+            res.owner
+        };
+        let bob = Some("bob".to_string());
+        assert_eq!([0, 1, 2, 3, 4].map(|height| owner(&deps, height)), [None, bob.clone(), bob, Some("alice".to_string()), None]);
+
+        let balance = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, height: u64| {
+            let query_msg = crate::msg::QueryMsg::TokenBalanceAtHeight { token_id: "1".to_string(), height: Some(at(height).block.height) };
+            let res: crate::msg::TokenBalanceAtHeightResponse = from_binary(&entry::query(deps.as_ref(), at(4), query_msg).unwrap()).unwrap();
+            res.native
+        };
+        assert_eq!([0, 1, 2, 3, 4].map(|height| balance(&deps, height)), [vec![], coins(1000, "earth"), coins(1100, "earth"), coins(1100, "earth"), vec![]]);
+
+        let holds = |deps: &OwnedDeps<MockStorage, MockApi, MockQuerier>, owner: &str, height: u64| {
+            let query_msg = crate::msg::QueryMsg::OwnsTokenAtHeight {
+                owner: owner.to_string(),
+                token_id: "1".to_string(),
+                height: Some(at(height).block.height),
+            };
+            let res: crate::msg::OwnsTokenAtHeightResponse = from_binary(&entry::query(deps.as_ref(), at(4), query_msg).unwrap()).unwrap();
+            res.owns
+        };
+        assert_eq!([0, 1, 2, 3, 4].map(|height| holds(&deps, "bob", height)), [false, true, true, false, false]);
+        assert_eq!([0, 1, 2, 3, 4].map(|height| holds(&deps, "alice", height)), [false, false, false, true, false]);
+    }
+
+    #[test]
+    fn approvals_and_operators() {
+        let mut deps = mock_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata::default(),
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap();
+
+        let exec_msg = crate::msg::ExecuteMsg::Approve { spender: "market".to_string(), token_id: "1".to_string(), expires: None };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+        let exec_msg = crate::msg::ExecuteMsg::ApproveAll { operator: "dao".to_string(), expires: None };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::Approvals { token_id: "1".to_string(), include_expired: None };
+        let res : cw721::ApprovalsResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.approvals.len(), 1);
+        assert_eq!(res.approvals[0].spender, "market");
+
+        let query_msg = crate::msg::QueryMsg::AllOperators { owner: "bob".to_string(), include_expired: None, start_after: None, limit: None };
+        let res : cw721::OperatorsResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.operators.len(), 1);
+        assert_eq!(res.operators[0].spender, "dao");
+
+        // the approved spender can move the token
+        let exec_msg = crate::msg::ExecuteMsg::TransferNft { recipient: "alice".to_string(), token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("market", &[]), exec_msg).unwrap();
+
+        let exec_msg = crate::msg::ExecuteMsg::RevokeAll { operator: "dao".to_string() };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+        let query_msg = crate::msg::QueryMsg::AllOperators { owner: "bob".to_string(), include_expired: None, start_after: None, limit: None };
+        let res : cw721::OperatorsResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert!(res.operators.is_empty());
+    }
+
+    #[test]
+    fn custom_messages_do_not_convert() {
+        let msg = crate::msg::ExecuteMsg::<Metadata>::Deposit { token_id: "1".to_string() };
+        let res: Result<cw721_base::ExecuteMsg<Metadata, Empty>, _> = msg.try_into();
+        assert_eq!(res.unwrap_err(), ContractError::UnsupportedMessage {});
+
+        let res: Result<cw721_base::QueryMsg<Empty>, _> = crate::msg::QueryMsg::CollectionInfo {}.try_into();
+        assert_eq!(res.unwrap_err(), ContractError::UnsupportedMessage {});
+    }
+
+    #[test]
+    fn collection_info_tracks_totals() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            accepted_cw20s: Some(vec!["obol".to_string()]),
+            deposit_policy: Some(DepositPolicy::OwnerOrApproved),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        for (token_id, funds) in [("1", coins(100, "earth")), ("2", vec![coin(200, "earth"), coin(5, "fire")])] {
+            let mint_msg = MintMsg {
+                token_id: token_id.to_string(),
+                owner: "bob".to_string(),
+                token_uri: None,
+                extension: Metadata {
+                    native: Some(funds.clone()),
+                    cw20: None,
+                    locked_until: None,
+                    vesting: None,
+                },
+            };
+            let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+            entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &funds), exec_msg).unwrap();
+        }
+
+        let exec_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "bob".to_string(),
+            amount: Uint128::new(30),
+            msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "1".to_string() }).unwrap(),
+        });
+        entry::execute(deps.as_mut(), mock_env(), mock_info("obol", &[]), exec_msg).unwrap();
+        let exec_msg = crate::msg::ExecuteMsg::Deposit { token_id: "1".to_string() };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &coins(50, "air")), exec_msg).unwrap();
+        let exec_msg = crate::msg::ExecuteMsg::Withdraw { token_id: "2".to_string(), native: coins(5, "fire"), cw20: vec![] };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+        let exec_msg = crate::msg::ExecuteMsg::Burn { token_id: "2".to_string() };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::CollectionInfo {};
+        let res : crate::msg::CollectionInfoResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res, crate::msg::CollectionInfoResponse {
+            native: vec![coin(50, "air"), coin(100, "earth")],
+            cw20: vec![Cw20 { contract_address: "obol".to_string(), amount: Uint128::new(30) }],
+            num_tokens: 1,
+            minter: CREATOR.to_string(),
+            config: Config {
+                deposit_policy: DepositPolicy::OwnerOrApproved,
+                accepted_cw20s: vec!["obol".to_string()],
+                unbonding_period: DEFAULT_UNBONDING_PERIOD,
+                locked_transfers: TransferPolicy::Allowed,
+                ..Default::default()
+            },
+        });
+    }
+
+    #[test]
+    fn token_balance_checks_holdings() {
+        let mut deps = mock_dependencies();
+        deps.querier.update_wasm(|query| match query {
+            WasmQuery::Smart { contract_addr, .. } if contract_addr == "obol" => SystemResult::Ok(ContractResult::Ok(
+                to_binary(&cw20::BalanceResponse { balance: Uint128::new(30) }).unwrap(),
+            )),
+            _ => SystemResult::Err(SystemError::UnsupportedRequest { kind: "wasm".to_string() }),
+        });
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            accepted_cw20s: Some(vec!["obol".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(1000, "earth"));
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+
+        let exec_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "bob".to_string(),
+            amount: Uint128::new(30),
+            msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "1".to_string() }).unwrap(),
+        });
+        entry::execute(deps.as_mut(), mock_env(), mock_info("obol", &[]), exec_msg).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::TokenBalance { token_id: "1".to_string() };
+        let res : crate::msg::TokenBalanceResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg.clone()).unwrap()).unwrap();
+        assert!(res.backed);
+        assert_eq!(res.metadata.native, Some(coins(1000, "earth")));
+
+        // the contract loses coins behind the tokens' back
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(500, "earth"));
+
+        let res : crate::msg::TokenBalanceResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert!(!res.backed);
+    }
+
+    #[test]
+    fn updates_cannot_leave_contract_insolvent() {
+        let mut deps = mock_dependencies_with_balance(&coins(1000, "earth"));
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+
+        // the minter can't make the token claim more than the contract holds
+        let exec_msg = crate::msg::ExecuteMsg::UpdateMetadata {
+            token_id: "1".to_string(),
+            token_uri: Some("json".to_string()),
+            native: Some(coins(2000, "earth")),
+            cw20: None,
+        };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::Insolvent {
+            asset: "earth".to_string(),
+            held: Uint128::new(1000),
+            escrowed: Uint128::new(2000),
+        });
+
+    }
+
+    #[test]
+    fn solvency_compares_escrow_with_holdings() {
+        let mut deps = mock_dependencies_with_balance(&coins(1000, "earth"));
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            accepted_cw20s: Some(vec!["obol".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: Some(coins(1000, "earth")),
+                cw20: None,
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg);
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &coins(1000, "earth")), exec_msg).unwrap();
+
+        let query_msg = crate::msg::QueryMsg::Solvency {};
+        let res : crate::msg::SolvencyResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg.clone()).unwrap()).unwrap();
+        assert!(res.solvent);
+
+        // the contract loses coins
+        deps.querier.update_balance(MOCK_CONTRACT_ADDR, coins(900, "earth"));
+        let res : crate::msg::SolvencyResponse = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res, crate::msg::SolvencyResponse {
+            solvent: false,
+            escrowed_native: coins(1000, "earth"),
+            escrowed_cw20: vec![],
+            held_native: coins(900, "earth"),
+            held_cw20: vec![],
+            rewards_native: vec![],
+            rewards_cw20: vec![],
+        });
+
+        // anything making the shortfall worse fails, exits still go through
+        let exec_msg = crate::msg::ExecuteMsg::UpdateMetadata {
+            token_id: "1".to_string(),
+            token_uri: None,
+            native: Some(coins(1001, "earth")),
+            cw20: None,
+        };
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::Insolvent {
+            asset: "earth".to_string(),
+            held: Uint128::new(900),
+            escrowed: Uint128::new(1001),
+        });
+        let exec_msg = crate::msg::ExecuteMsg::Withdraw { token_id: "1".to_string(), native: coins(100, "earth"), cw20: vec![] };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+
+        // only the assets a message touches are checked, a cw20 that can't be queried
+        // any more holds up nothing else
+        deps.querier.update_wasm(|_| {
+            SystemResult::Ok(ContractResult::Ok(to_binary(&cw20::BalanceResponse { balance: Uint128::new(1) }).unwrap()))
+        });
+        let exec_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "bob".to_string(),
+            amount: Uint128::new(1),
+            msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "1".to_string() }).unwrap(),
+        });
+        entry::execute(deps.as_mut(), mock_env(), mock_info("obol", &[]), exec_msg).unwrap();
+        deps.querier.update_wasm(|_| SystemResult::Err(SystemError::UnsupportedRequest { kind: "wasm".to_string() }));
+        let exec_msg = crate::msg::ExecuteMsg::Withdraw { token_id: "1".to_string(), native: coins(100, "earth"), cw20: vec![] };
+        entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+        let exec_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "bob".to_string(),
+            amount: Uint128::new(1),
+            msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "1".to_string() }).unwrap(),
+        });
+        entry::execute(deps.as_mut(), mock_env(), mock_info("obol", &[]), exec_msg).unwrap_err();
+    }
+
+    #[test]
+    fn cw20_deposit_to_token() {
+        let mut deps = funded_dependencies();
+
+        let info = mock_info(CREATOR, &[]);
+        let init_msg = InstantiateMsg {
+            name: "Greeks".to_string(),
+            symbol: "drachma".to_string(),
+            minter: CREATOR.to_string(),
+            accepted_cw20s: Some(vec!["obol".to_string()]),
+            ..Default::default()
+        };
+        entry::instantiate(deps.as_mut(), mock_env(), info, init_msg).unwrap();
+
+        // cw20 balances can't be declared on mint
+        let mint_msg = MintMsg {
+            token_id: "1".to_string(),
+            owner: "bob".to_string(),
+            token_uri: None,
+            extension: Metadata {
+                native: None,
+                cw20: Some(vec![Cw20 { contract_address: "obol".to_string(), amount: Uint128::new(50) }]),
+                locked_until: None,
+                vesting: None,
+            },
+        };
+        let exec_msg = crate::msg::ExecuteMsg::Mint(mint_msg.clone());
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::UnbackedCw20 {});
+
+        let exec_msg = crate::msg::ExecuteMsg::Mint(MintMsg { extension: Metadata::default(), ..mint_msg });
+        entry::execute(deps.as_mut(), mock_env(), mock_info(CREATOR, &[]), exec_msg).unwrap();
+
+        // only listed cw20 contracts can deposit
+        let exec_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+            sender: "alice".to_string(),
+            amount: Uint128::new(1),
+            msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "1".to_string() }).unwrap(),
+        });
+        let err = entry::execute(deps.as_mut(), mock_env(), mock_info("fakecoin", &[]), exec_msg).unwrap_err();
+        assert_eq!(err, ContractError::InvalidCw20 { contract_address: "fakecoin".to_string() });
+
+        // two deposits from the same cw20 contract end up in a single entry
+        for amount in [50u128, 25] {
+            let exec_msg = crate::msg::ExecuteMsg::Receive(Cw20ReceiveMsg {
+                sender: "alice".to_string(),
+                amount: Uint128::new(amount),
+                msg: to_binary(&crate::msg::ReceiveMsg::DepositToToken { token_id: "1".to_string() }).unwrap(),
+            });
+            entry::execute(deps.as_mut(), mock_env(), mock_info("obol", &[]), exec_msg).unwrap();
+        }
+
+        let query_msg = crate::msg::QueryMsg::NftInfo { token_id: "1".to_string() };
+        let res : NftInfoResponse<Metadata> = from_binary(&entry::query(deps.as_ref(), mock_env(), query_msg).unwrap()).unwrap();
+        assert_eq!(res.extension.cw20, Some(vec![Cw20 { contract_address: "obol".to_string(), amount: Uint128::new(75) }]));
+
+        // burning transfers the cw20 back to the owner
+        let exec_msg = crate::msg::ExecuteMsg::Burn { token_id: "1".to_string() };
+        let res = entry::execute(deps.as_mut(), mock_env(), mock_info("bob", &[]), exec_msg).unwrap();
+        assert_eq!(res.messages, vec![SubMsg::new(WasmMsg::Execute {
+            contract_addr: "obol".to_string(),
+            msg: to_binary(&Cw20ExecuteMsg::Transfer { recipient: "bob".to_string(), amount: Uint128::new(75) }).unwrap(),
+            funds: vec![],
+        })]);
+    }
 }
\ No newline at end of file
@@ -0,0 +1,146 @@
+use cosmwasm_std::{Coin, StdError, Uint128};
+use cw721::Expiration;
+use thiserror::Error;
+
+#[derive(Error, Debug, PartialEq)]
+pub enum ContractError {
+    #[error("{0}")]
+    Std(#[from] StdError),
+
+    #[error("{0}")]
+    Base(#[from] cw721_base::ContractError),
+
+    #[error("Unauthorized")]
+    Unauthorized {},
+
+    #[error("Token {token_id} not found")]
+    TokenNotFound { token_id: String },
+
+    #[error("Token {token_id} already exists")]
+    TokenExists { token_id: String },
+
+    #[error("Message is not supported by this contract")]
+    UnsupportedMessage {},
+
+    #[error("Sent funds {received:?} do not match the native balance {expected:?} of the token")]
+    FundsMismatch {
+        expected: Vec<Coin>,
+        received: Vec<Coin>,
+    },
+
+    #[error("Contract would hold {held} {asset} while its tokens escrow {escrowed}")]
+    Insolvent {
+        asset: String,
+        held: Uint128,
+        escrowed: Uint128,
+    },
+
+    #[error("Metadata updates cannot change escrowed balances, use deposits and withdrawals")]
+    FinancialMetadataUpdate {},
+
+    #[error("The non-financial metadata policy cannot be reverted")]
+    MetadataPolicyLocked {},
+
+    #[error("No funds sent")]
+    NoFunds {},
+
+    #[error("{denom} is not a reward denom")]
+    InvalidRewardDenom { denom: String },
+
+    #[error("{contract_address} is not an accepted cw20")]
+    InvalidCw20 { contract_address: String },
+
+    #[error("Rewards cannot stream for {duration} seconds")]
+    InvalidDuration { duration: u64 },
+
+    #[error("No principal is staked to distribute rewards over")]
+    NoPrincipal {},
+
+    #[error("No validators are configured")]
+    NoValidators {},
+
+    #[error("{validator} is not a validator")]
+    UnknownValidator { validator: String },
+
+    #[error("Token {token_id} has no principal left to delegate")]
+    NothingToDelegate { token_id: String },
+
+    #[error("Token {token_id} has no delegated principal")]
+    NothingToUndelegate { token_id: String },
+
+    #[error("Token {token_id} would hold less than its delegated and unbonding principal")]
+    PrincipalLocked { token_id: String },
+
+    #[error("Token {token_id} is being redeemed")]
+    TokenRedeeming { token_id: String },
+
+    #[error("Token {token_id} is not being redeemed")]
+    NotRedeeming { token_id: String },
+
+    #[error("Vesting must start no later than its cliff and end after both")]
+    InvalidVesting {},
+
+    #[error("Token {token_id} would hold less than its unvested assets")]
+    Unvested { token_id: String },
+
+    #[error("Early exits are not enabled")]
+    EarlyExitDisabled {},
+
+    #[error("Token {token_id} is not locked")]
+    NotLocked { token_id: String },
+
+    #[error("The early exit penalty can't be more than the whole escrow")]
+    InvalidPenalty {},
+
+    #[error("Penalties can only go to stakers if the collection has a staking denom")]
+    NoStakers {},
+
+    #[error("Early exits can't be replaced and disabled at once")]
+    EarlyExitConflict {},
+
+    #[error("Token {token_id} is locked until {locked_until}")]
+    TokenLocked { token_id: String, locked_until: Expiration },
+
+    #[error("No unbonding batch is due")]
+    NoBatchDue {},
+
+    #[error("There are no delegator rewards to compound")]
+    NothingToCompound {},
+
+    #[error("Token {token_id} is unbonding until {release_at}")]
+    StillUnbonding { token_id: String, release_at: Expiration },
+
+    #[error("Cw20 balances cannot be minted, send them to the token with a DepositToToken hook")]
+    UnbackedCw20 {},
+
+    #[error("No tokens to merge")]
+    NothingToMerge {},
+
+    #[error("Token {token_id} is listed more than once")]
+    DuplicateTokenId { token_id: String },
+
+    #[error("{asset} has more than one voting weight")]
+    DuplicateVotingWeight { asset: String },
+
+    #[error("Token {token_id} belongs to a different owner")]
+    OwnerMismatch { token_id: String },
+
+    #[error("A token must be split into at least two parts")]
+    TooFewParts {},
+
+    #[error("A token can be split into at most {max} parts")]
+    TooManyParts { max: u32 },
+
+    #[error("Split parts must hold some assets")]
+    EmptyPart {},
+
+    #[error("Split parts do not add up to the escrow of token {token_id}")]
+    SplitMismatch { token_id: String },
+
+    #[error("Cannot withdraw {requested} {asset}, the token only holds {available}")]
+    Overdraft {
+        asset: String,
+        available: Uint128,
+        requested: Uint128,
+    },
+}
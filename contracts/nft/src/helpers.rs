@@ -2,7 +2,7 @@ use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 
 use cosmwasm_std::{
-    to_binary, Addr, CosmosMsg, CustomQuery, Querier, QuerierWrapper, StdResult, WasmMsg, WasmQuery,
+    to_binary, Addr, Coin, CosmosMsg, CustomQuery, Querier, QuerierWrapper, StdResult, WasmMsg, WasmQuery,
 };
 
 //use crate::msg::{ExecuteMsg, };
@@ -14,6 +14,7 @@ pub use cw721::{OwnerOfResponse, TokensResponse, NftInfoResponse};
 use crate::contract::Metadata;
 pub use crate::msg::QueryMsg;
 pub use crate::msg::ExecuteMsg;
+use crate::msg::TokenBalanceResponse;
 
 
 // use crate::msg::ExecuteMsg;
@@ -38,6 +39,17 @@ impl NftContract {
         .into())
     }
 
+    /// Same as `call`, attaching `funds` to the message (e.g. the native coins escrowed on mint)
+    pub fn call_with_funds<T: serde::Serialize>(&self, msg: ExecuteMsg<T>, funds: Vec<Coin>) -> StdResult<CosmosMsg> {
+        let msg = to_binary(&msg)?;
+        Ok(WasmMsg::Execute {
+            contract_addr: self.addr().into(),
+            msg,
+            funds,
+        }
+        .into())
+    }
+
     // pub fn call<T: Into<ExecuteMsg<T>>>(&self, msg: T) -> StdResult<CosmosMsg> {
     //     let msg = to_binary(&msg)?;              
     //     Ok(WasmMsg::Execute {
@@ -56,7 +68,7 @@ impl NftContract {
         T: Into<String>,
         CQ: CustomQuery,
     {
-        let msg: QueryMsg = QueryMsg::OwnerOf { token_id:token_id, include_expired:None };
+        let msg: QueryMsg = QueryMsg::OwnerOf { token_id, include_expired:None };
         let query = WasmQuery::Smart { contract_addr: self.addr().into(), msg: to_binary(&msg)? }.into();
         let res: OwnerOfResponse = QuerierWrapper::<CQ>::new(querier).query(&query)?;
         Ok(res)
@@ -82,10 +94,21 @@ impl NftContract {
         T: Into<String>,
         CQ: CustomQuery,
     {
-        let msg: QueryMsg = QueryMsg::NftInfo { token_id: token_id };
+        let msg: QueryMsg = QueryMsg::NftInfo { token_id };
         let query = WasmQuery::Smart { contract_addr: self.addr().into(), msg: to_binary(&msg)? }.into();
         let res: NftInfoResponse<_> = QuerierWrapper::<CQ>::new(querier).query(&query)?;
         Ok(res)
     }
+
+    /// Get the escrowed assets of an NFT and whether the contract holdings cover them
+    pub fn get_token_balance<Q, CQ>(&self, querier: &Q, token_id: String) -> StdResult<TokenBalanceResponse>
+    where
+        Q: Querier,
+        CQ: CustomQuery,
+    {
+        let msg: QueryMsg = QueryMsg::TokenBalance { token_id };
+        let query = WasmQuery::Smart { contract_addr: self.addr().into(), msg: to_binary(&msg)? }.into();
+        QuerierWrapper::<CQ>::new(querier).query(&query)
+    }
     
 }
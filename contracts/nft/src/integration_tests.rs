@@ -1,7 +1,7 @@
 #[cfg(test)]
 mod tests {
     use crate::{msg::{ExecuteMsg}, helpers::NftContract, contract::Metadata};
-    use cosmwasm_std::{coin, coins, to_binary, Addr, Coin, Empty, Uint128};
+    use cosmwasm_std::{coins, Addr, Coin, Empty, Uint128};
     use cw721::OwnerOfResponse;
     use cw_multi_test::{App, AppBuilder, Contract, ContractWrapper, Executor};
 
@@ -47,6 +47,17 @@ mod tests {
                     }],
                 )
                 .unwrap();
+            router
+                .bank
+                .init_balance(
+                    storage,
+                    &Addr::unchecked(MINTER),
+                    vec![Coin {
+                        denom: NATIVE_DENOM.to_string(),
+                        amount: Uint128::new(10000),
+                    }],
+                )
+                .unwrap();
         })
     }
 
@@ -61,7 +72,7 @@ mod tests {
             .instantiate_contract(
                 code_id,
                 Addr::unchecked(ADMIN),
-                &crate::contract::InstantiateMsg{name, symbol, minter},
+                &crate::contract::InstantiateMsg{name, symbol, minter, ..Default::default()},
                 &[],
                 "nft",
                 None,
@@ -126,7 +137,9 @@ mod tests {
 
         let metadata = Metadata{ 
             native: Some(coins(1000, NATIVE_DENOM)), 
-            cw20: None };
+            cw20: None,
+            locked_until: None,
+            vesting: None };
 
         //mint NFT to User
         let mint_msg = crate::contract::MintMsg{
@@ -137,34 +150,33 @@ mod tests {
         };
 
         let msg:ExecuteMsg<Metadata> = crate::msg::ExecuteMsg::Mint(mint_msg);
-        let cosmos_msg = cw721_contract.call(msg.into()).unwrap();
+        let cosmos_msg = cw721_contract.call_with_funds(msg, coins(1000, NATIVE_DENOM)).unwrap();
         app.execute(Addr::unchecked(MINTER), cosmos_msg).unwrap();
 
+        //check to see if User is the owner.
+        let owner: OwnerOfResponse = cw721_contract
+            .get_owner::<_, String, Empty>(&app, TOKEN_ID.to_string())
+            .unwrap();
+        assert_eq!(owner.owner, USER1.to_string());
 
-        
-         //check to see if User is the owner.
-        //  let owner = get_owner(&app, &cw721_contract, "0".to_string());
-        //  assert_eq!(owner.owner, USER1.to_string());
-        //  println!("{:?}", owner);
-
-
-        //  //get owner of NFT with token_id "0"
-        // let owner = get_owner_of(&app, &nft_contract, TOKEN_ID.to_string());
-        // assert_eq!(owner.owner, USER1.to_string());
+        // the contract now holds the escrowed coins
+        let balance = app.wrap().query_balance(cw721_contract.addr(), NATIVE_DENOM).unwrap();
+        assert_eq!(balance.amount, Uint128::new(1000));
 
-        let new_metadata = Metadata{ 
-            native: Some(coins(2000, NATIVE_DENOM)), 
-            cw20: None };
-            
-        let msg:ExecuteMsg<Metadata> = crate::msg::ExecuteMsg::UpdateMetadata { 
-            token_id: TOKEN_ID.to_string(), 
-            token_uri: "token_uri_url2".to_string(), 
-            metadata: new_metadata 
-        };
+        let balance = cw721_contract
+            .get_token_balance::<_, Empty>(&app, TOKEN_ID.to_string())
+            .unwrap();
+        assert!(balance.backed);
 
-        // let cosmos_msg = cw721_contract.call(msg).unwrap();
-        // app.execute(Addr::unchecked(MINTER), cosmos_msg).unwrap();        
+        // burning hands them back to the owner
+        let msg:ExecuteMsg<Metadata> = crate::msg::ExecuteMsg::Burn { token_id: TOKEN_ID.to_string() };
+        let cosmos_msg = cw721_contract.call_with_funds(msg, vec![]).unwrap();
+        app.execute(Addr::unchecked(USER1), cosmos_msg).unwrap();
 
+        let balance = app.wrap().query_balance(cw721_contract.addr(), NATIVE_DENOM).unwrap();
+        assert_eq!(balance.amount, Uint128::zero());
+        let balance = app.wrap().query_balance(USER1, NATIVE_DENOM).unwrap();
+        assert_eq!(balance.amount, Uint128::new(11000));
     }
 
 }
\ No newline at end of file
@@ -1,5 +1,6 @@
 pub mod contract;
+pub mod error;
 pub mod helpers;
 pub mod msg;
+pub mod state;
 pub mod integration_tests;
-
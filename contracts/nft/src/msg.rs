@@ -1,27 +1,186 @@
-use cosmwasm_std::{Binary, Empty};
-use cosmwasm_schema::{cw_serde, QueryResponses};
+use cosmwasm_std::{Binary, Coin, Empty, Uint128};
+use cosmwasm_schema::cw_serde;
+use cw20::Cw20ReceiveMsg;
+use cw721::Expiration;
 use cw721_base::MintMsg;
 use schemars::JsonSchema;
 use cw721_base::msg::QueryMsg as Cw721QueryMsg;
 use cw721_base::ExecuteMsg as Cw721ExecuteMsg;
 use serde::{Serialize, Deserialize};
 
-use crate::contract::Metadata;
+use crate::contract::{Cw20, Metadata};
+use crate::error::ContractError;
+use crate::state::{Config, DepositPolicy, EarlyExit, MetadataPolicy, VotingWeight, Redemption, TransferPolicy, Unbonding};
+
+#[cw_serde]
+#[derive(Default)]
+pub struct InstantiateMsg {
+    /// Name of the NFT contract
+    pub name: String,
+    /// Symbol of the NFT contract
+    pub symbol: String,
+    /// The minter is the only one who can create new NFTs
+    /// and change the configuration below
+    pub minter: String,
+    /// Who may deposit into existing tokens, anyone if unset
+    pub deposit_policy: Option<DepositPolicy>,
+    /// What `UpdateMetadata` may change, unrestricted if unset
+    pub metadata_policy: Option<MetadataPolicy>,
+    /// Native denom that counts as staked principal, tokens earn no rewards if unset.
+    /// It cannot be changed later
+    pub staking_denom: Option<String>,
+    /// Native denoms and cw20 contract addresses that can be paid out as rewards,
+    /// none if unset
+    pub reward_denoms: Option<Vec<String>>,
+    /// Cw20 contracts whose tokens can be deposited into a token, none if unset
+    pub accepted_cw20s: Option<Vec<String>>,
+    /// Validators tokens delegate their principal to, none if unset
+    pub validators: Option<Vec<String>>,
+    /// Unbonding time of the chain in seconds, 21 days if unset
+    pub unbonding_period: Option<u64>,
+    /// Whether tokens waiting for their principal to unbond can be transferred,
+    /// locked if unset
+    pub unbonding_transfers: Option<TransferPolicy>,
+    /// Whether tokens minted with a `locked_until` in the future can be transferred
+    /// before then, allowed if unset
+    pub locked_transfers: Option<TransferPolicy>,
+    /// Penalty for burning a token before its lock-up is over, not allowed if unset
+    pub early_exit: Option<EarlyExit>,
+    /// Voting power of an escrowed unit of each asset, listed once, tokens have none if unset.
+    /// It cannot be changed later
+    pub voting_weights: Option<Vec<VotingWeight>>,
+    /// Whether tokens compound their delegator rewards unless they opt out, off if unset
+    pub auto_compound: Option<bool>,
+}
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
 #[serde(rename_all = "snake_case")]
 pub enum ExecuteMsg<T> {
-    /// Mint a new NFT, can only be called by the contract minter
+    /// Mint a new NFT, can only be called by the contract minter. A `locked_until`
+    /// in the extension keeps its escrow from being burned, redeemed or withdrawn
+    /// until then, a `vesting` schedule only lets vested assets out
     Mint(MintMsg<T>),
-    /// Updates metadata of the NFT
-    UpdateMetadata { token_id: String, token_uri: String, metadata: Metadata },
-    /// Burn an NFT the sender has access to
+    /// Updates metadata of the NFT, can only be called by the contract minter.
+    /// Unset fields are left untouched, an empty uri or list clears the field.
+    /// Balances can only be changed under `MetadataPolicy::Unrestricted`
+    UpdateMetadata {
+        token_id: String,
+        token_uri: Option<String>,
+        native: Option<Vec<Coin>>,
+        cw20: Option<Vec<Cw20>>,
+    },
+    /// Burn an NFT the sender has access to, its escrowed assets go to the owner.
+    /// If part of its principal is delegated or unbonding, it is undelegated instead
+    /// and the token can be claimed with `ClaimUnbonded` once it is back
     Burn { token_id: String },
+    /// Burn an NFT the sender has access to and pay its escrowed assets out
+    /// to `recipient`, or to the owner if unset. Delegated principal is unbonded
+    /// first, like for `Burn`
+    Redeem { token_id: String, recipient: Option<String> },
+    /// Burn a token before its lock-up is over, giving up the configured share of its
+    /// escrow, and pay the rest out to `recipient`, or to the owner if unset. Its
+    /// principal has to be undelegated first
+    ExitEarly { token_id: String, recipient: Option<String> },
+    /// Burn a redeemed token whose principal is done unbonding and pay out its escrow.
+    /// Anyone can call it, the assets go where the redemption said
+    ClaimUnbonded { token_id: String },
+    /// Add the sent funds to the token's native escrow. Who can deposit depends
+    /// on the configured `DepositPolicy`
+    Deposit { token_id: String },
+    /// Take part of the token's escrowed assets out without burning it, they are paid
+    /// to the owner. Fails if the token holds less than requested of any asset
+    Withdraw { token_id: String, native: Vec<Coin>, cw20: Vec<Cw20> },
     /// Transfer is a base message to move a token to another account without triggering actions
     TransferNft { recipient: String, token_id: String },
     /// Send is a base message to transfer a token to a contract and trigger an action
     /// on the receiving contract.
     SendNft {contract: String,token_id: String,msg: Binary,},    
+    /// Allows operator to transfer / send the token from the owner's account.
+    /// If expiration is set, then this allowance has a time/height limit
+    Approve { spender: String, token_id: String, expires: Option<Expiration> },
+    /// Remove previously granted Approval
+    Revoke { spender: String, token_id: String },
+    /// Allows operator to transfer / send any token from the owner's account.
+    /// If expiration is set, then this allowance has a time/height limit
+    ApproveAll { operator: String, expires: Option<Expiration> },
+    /// Remove previously granted ApproveAll permission
+    RevokeAll { operator: String },
+    /// Sum the escrowed assets of every token in `token_ids` into the `into` token
+    /// and burn them. All tokens must share an owner the sender can act for, only
+    /// `into` may still be locked and none of them may still be vesting
+    Merge { token_ids: Vec<String>, into: String },
+    /// Burn the token and mint one new token per part to the same owner, with ids
+    /// `{token_id}-1`, `{token_id}-2`, ... The parts must add up to the escrow exactly,
+    /// none of them can be empty and there can be at most ten
+    Split { token_id: String, parts: Vec<Metadata> },
+    /// Cw20 receive hook, the wrapped `msg` must be a `ReceiveMsg`
+    Receive(Cw20ReceiveMsg),
+    /// Share the sent funds between all tokens pro-rata to their staked principal.
+    /// Only the configured reward denoms are accepted. Denoms that are still
+    /// streaming take the funds into their stream instead
+    DistributeRewards {},
+    /// Stream the sent `denom` to all tokens pro-rata to their staked principal,
+    /// linearly over the next `duration` seconds. If the denom is still streaming,
+    /// the funds join that stream, which ends no earlier than it would have and
+    /// keeps paying out at least as fast
+    FundRewards { denom: String, duration: u64 },
+    /// Pay the pending rewards of every token in `token_ids` to its owner, with the
+    /// delegator rewards its bonded principal earned up to the last withdrawal
+    ClaimRewards { token_ids: Vec<String> },
+    /// Delegate the principal of the token that is not delegated yet, spread evenly
+    /// over the configured validators
+    Delegate { token_id: String },
+    /// Undelegate the delegated principal of the token. Undelegations are sent in
+    /// batches, at most one every sixth of the unbonding period, and the principal
+    /// stays locked in the token until its batch is done unbonding
+    Undelegate { token_id: String },
+    /// Send the undelegations queued since the last batch, once a sixth of the
+    /// unbonding period has passed. Anyone can call it
+    Unbond {},
+    /// Choose whether the token compounds its delegator rewards, or follow the
+    /// collection setting if unset
+    SetAutoCompound { token_id: String, auto_compound: Option<bool> },
+    /// Withdraw the delegator rewards of the contract and pay up to `limit` tokens with
+    /// a stake, after `start_after`, what their bonded principal earned since they last
+    /// got their share, rewards paid out along with delegation changes included. Tokens
+    /// that compound get their share added to their escrow, the staking denom being
+    /// delegated again, the others can claim it with `ClaimRewards`. Anyone can call it,
+    /// the last token paid is in the `last_token_id` attribute
+    Compound { start_after: Option<String>, limit: Option<u32> },
+    /// Change the contract configuration, can only be called by the contract minter.
+    /// Unset fields are left untouched
+    UpdateConfig(ConfigUpdate),
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub struct ConfigUpdate {
+    pub deposit_policy: Option<DepositPolicy>,
+    pub metadata_policy: Option<MetadataPolicy>,
+    pub reward_denoms: Option<Vec<String>>,
+    /// Replaces the cw20 contracts that can be deposited, tokens keep what they hold
+    pub accepted_cw20s: Option<Vec<String>>,
+    /// Delegations to validators left out of the new set are redelegated to it
+    pub validators: Option<Vec<String>>,
+    pub unbonding_period: Option<u64>,
+    pub unbonding_transfers: Option<TransferPolicy>,
+    pub locked_transfers: Option<TransferPolicy>,
+    /// Replaces the early exit terms, a penalty of one forfeits the whole escrow
+    pub early_exit: Option<EarlyExit>,
+    /// Turns early exits off again if true, not along with `early_exit`
+    pub disable_early_exit: Option<bool>,
+    pub auto_compound: Option<bool>,
+}
+
+/// Hook messages accepted through a cw20 `Send` to this contract
+#[cw_serde]
+pub enum ReceiveMsg {
+    /// Credit the sent cw20 amount to the escrow of the given token, only from a cw20
+    /// listed in `accepted_cw20s`
+    DepositToToken { token_id: String },
+    /// Stream the sent cw20 as staking rewards over the next `duration` seconds,
+    /// like `ExecuteMsg::FundRewards`
+    FundRewards { duration: u64 },
 }
 
 #[derive(Serialize, Deserialize, Clone, Debug, PartialEq, JsonSchema)]
@@ -34,6 +193,24 @@ pub enum QueryMsg {
         token_id: String,
         include_expired: Option<bool>,
     },
+    /// Return operator that can access all of the owner's tokens.
+    Approval {
+        token_id: String,
+        spender: String,
+        include_expired: Option<bool>,
+    },
+    /// Return approvals that a token has
+    Approvals {
+        token_id: String,
+        include_expired: Option<bool>,
+    },
+    /// List all operators that can access all of the owner's tokens
+    AllOperators {
+        owner: String,
+        include_expired: Option<bool>,
+        start_after: Option<String>,
+        limit: Option<u32>,
+    },
     /// Total number of tokens issued
     NumTokens {},
     /// With MetaData Extension.
@@ -67,8 +244,40 @@ pub enum QueryMsg {
     },
     /// Return the minter
     Minter {},
-    /// Extension query
+    /// Extension query.
+    /// Returns the assets escrowed across all tokens and the collection configuration
     CollectionInfo {},
+    /// Extension query.
+    /// Returns the assets escrowed for one token and whether the contract holdings cover them
+    TokenBalance { token_id: String },
+    /// Extension query.
+    /// Sums the escrow of every token and compares it with the contract holdings
+    Solvency {},
+    /// Extension query.
+    /// Returns the staking rewards the token can claim
+    PendingRewards { token_id: String },
+    /// Extension query.
+    /// Returns how much of the token's principal is delegated or unbonding
+    Delegation { token_id: String },
+    /// Extension query.
+    /// Returns how much of the token's escrow has vested and can be withdrawn
+    Vested { token_id: String },
+    /// Extension query, as served by DAO DAO voting modules.
+    /// Returns the voting power of the tokens `address` owned at `height`, the
+    /// current height if unset
+    VotingPowerAtHeight { address: String, height: Option<u64> },
+    /// Extension query, as served by DAO DAO voting modules.
+    /// Returns the voting power of all tokens at `height`, the current height if unset
+    TotalPowerAtHeight { height: Option<u64> },
+    /// Extension query.
+    /// Returns who owned the token at `height`, the current height if unset
+    OwnerOfAtHeight { token_id: String, height: Option<u64> },
+    /// Extension query.
+    /// Returns the assets escrowed for the token at `height`, the current height if unset
+    TokenBalanceAtHeight { token_id: String, height: Option<u64> },
+    /// Extension query.
+    /// Returns whether `owner` held the token at `height`, the current height if unset
+    OwnsTokenAtHeight { owner: String, token_id: String, height: Option<u64> },
 }
 
 /// Shows who can mint these tokens
@@ -77,64 +286,204 @@ pub struct MinterResponse {
     pub minter: String,
 }
 
-impl<T> From<ExecuteMsg<T>> for Cw721ExecuteMsg<T,Empty>
+#[cw_serde]
+pub struct CollectionInfoResponse {
+    /// Native coins escrowed across all tokens
+    pub native: Vec<Coin>,
+    /// Cw20 tokens escrowed across all tokens
+    pub cw20: Vec<Cw20>,
+    pub num_tokens: u64,
+    pub minter: String,
+    pub config: Config,
+}
+
+#[cw_serde]
+pub struct TokenBalanceResponse {
+    pub metadata: Metadata,
+    /// True when, for every asset of the token, the contract's bank or cw20 balance
+    /// is at least what is escrowed for that asset across all tokens
+    pub backed: bool,
+}
+
+#[cw_serde]
+pub struct SolvencyResponse {
+    /// True when the contract holds at least the escrowed amount of every asset
+    pub solvent: bool,
+    /// Native coins escrowed, summed over every token
+    pub escrowed_native: Vec<Coin>,
+    /// Cw20 tokens escrowed, summed over every token
+    pub escrowed_cw20: Vec<Cw20>,
+    /// Bank balance of the contract in each escrowed or reward denom
+    pub held_native: Vec<Coin>,
+    /// Cw20 balance of the contract in each escrowed or reward cw20
+    pub held_cw20: Vec<Cw20>,
+    /// Native staking rewards funded and not claimed yet, they have to be held
+    /// on top of the escrow
+    pub rewards_native: Vec<Coin>,
+    /// Cw20 staking rewards funded and not claimed yet
+    pub rewards_cw20: Vec<Cw20>,
+}
+
+#[cw_serde]
+pub struct DelegationResponse {
+    pub delegated: Uint128,
+    /// Undelegations of the token that are not over yet, those waiting for the next
+    /// batch release `never` until it is sent
+    pub unbonding: Vec<Unbonding>,
+    /// Principal that is neither delegated nor unbonding
+    pub liquid: Uint128,
+    /// Pending redemption of the token, if it was redeemed while unbonding
+    pub redemption: Option<Redemption>,
+    /// Whether `Compound` adds the delegator rewards of the token to its escrow
+    pub auto_compound: bool,
+}
+
+#[cw_serde]
+pub struct VestedResponse {
+    /// Native coins vested so far, including the ones already withdrawn
+    pub vested_native: Vec<Coin>,
+    /// Cw20 tokens vested so far, including the ones already withdrawn
+    pub vested_cw20: Vec<Cw20>,
+    /// Vested native coins still escrowed in the token
+    pub withdrawable_native: Vec<Coin>,
+    /// Vested cw20 tokens still escrowed in the token
+    pub withdrawable_cw20: Vec<Cw20>,
+}
+
+#[cw_serde]
+pub struct VotingPowerAtHeightResponse {
+    pub power: Uint128,
+    pub height: u64,
+}
+
+#[cw_serde]
+pub struct TotalPowerAtHeightResponse {
+    pub power: Uint128,
+    pub height: u64,
+}
+
+#[cw_serde]
+pub struct OwnerOfAtHeightResponse {
+    /// Unset if the token did not exist
+    pub owner: Option<String>,
+    pub height: u64,
+}
+
+#[cw_serde]
+pub struct OwnsTokenAtHeightResponse {
+    pub owns: bool,
+    pub height: u64,
+}
+
+#[cw_serde]
+pub struct TokenBalanceAtHeightResponse {
+    pub native: Vec<Coin>,
+    pub cw20: Vec<Cw20>,
+    pub height: u64,
+}
+
+#[cw_serde]
+pub struct PendingRewardsResponse {
+    pub native: Vec<Coin>,
+    pub cw20: Vec<Cw20>,
+}
+
+/// Only the cw721 part of `ExecuteMsg` converts, the angel messages are handled
+/// by this contract and return `ContractError::UnsupportedMessage`
+impl<T> TryFrom<ExecuteMsg<T>> for Cw721ExecuteMsg<T,Empty>
 {
-    fn from(msg: ExecuteMsg<T>) -> Cw721ExecuteMsg<T, Empty> {
+    type Error = ContractError;
+
+    fn try_from(msg: ExecuteMsg<T>) -> Result<Cw721ExecuteMsg<T, Empty>, ContractError> {
         match msg {
             ExecuteMsg::Mint(MintMsg {
                 token_id,
                 owner,
                 token_uri,
                 extension,
-            }) => Cw721ExecuteMsg::Mint(MintMsg {
+            }) => Ok(Cw721ExecuteMsg::Mint(MintMsg {
                 token_id,
                 owner,
                 token_uri,
                 extension,
-            }),
-            ExecuteMsg::Burn { token_id } => Cw721ExecuteMsg::Burn { token_id },
-            ExecuteMsg::TransferNft {recipient, token_id,} => Cw721ExecuteMsg::TransferNft {recipient, token_id,},
-            ExecuteMsg::SendNft {contract, token_id, msg,} => Cw721ExecuteMsg::SendNft {contract,token_id, msg,},
-            _ => unreachable!("Invalid ExecuteMsg"),
+            })),
+            ExecuteMsg::Burn { token_id } => Ok(Cw721ExecuteMsg::Burn { token_id }),
+            ExecuteMsg::TransferNft {recipient, token_id,} => Ok(Cw721ExecuteMsg::TransferNft {recipient, token_id,}),
+            ExecuteMsg::SendNft {contract, token_id, msg,} => Ok(Cw721ExecuteMsg::SendNft {contract,token_id, msg,}),
+            ExecuteMsg::Approve { spender, token_id, expires } => Ok(Cw721ExecuteMsg::Approve { spender, token_id, expires }),
+            ExecuteMsg::Revoke { spender, token_id } => Ok(Cw721ExecuteMsg::Revoke { spender, token_id }),
+            ExecuteMsg::ApproveAll { operator, expires } => Ok(Cw721ExecuteMsg::ApproveAll { operator, expires }),
+            ExecuteMsg::RevokeAll { operator } => Ok(Cw721ExecuteMsg::RevokeAll { operator }),
+            _ => Err(ContractError::UnsupportedMessage {}),
         }
     }
 }
 
-impl From<QueryMsg> for Cw721QueryMsg<Empty>                    
+/// Extension queries have no cw721 counterpart and return `ContractError::UnsupportedMessage`
+impl TryFrom<QueryMsg> for Cw721QueryMsg<Empty>                    
 {                       
-    fn from(msg: QueryMsg) -> Cw721QueryMsg<Empty> {              
+    type Error = ContractError;
+
+    fn try_from(msg: QueryMsg) -> Result<Cw721QueryMsg<Empty>, ContractError> {              
         match msg {
             QueryMsg::OwnerOf {
                 token_id,
                 include_expired,
-            } => Cw721QueryMsg::OwnerOf {
+            } => Ok(Cw721QueryMsg::OwnerOf {
+                token_id,
+                include_expired,
+            }),
+            QueryMsg::Approval {
                 token_id,
+                spender,
                 include_expired,
-            },
-            QueryMsg::NumTokens {} => Cw721QueryMsg::NumTokens {},
-            QueryMsg::ContractInfo {} => Cw721QueryMsg::ContractInfo {},
-            QueryMsg::NftInfo { token_id } => Cw721QueryMsg::NftInfo { token_id },
+            } => Ok(Cw721QueryMsg::Approval {
+                token_id,
+                spender,
+                include_expired,
+            }),
+            QueryMsg::Approvals {
+                token_id,
+                include_expired,
+            } => Ok(Cw721QueryMsg::Approvals {
+                token_id,
+                include_expired,
+            }),
+            QueryMsg::AllOperators {
+                owner,
+                include_expired,
+                start_after,
+                limit,
+            } => Ok(Cw721QueryMsg::AllOperators {
+                owner,
+                include_expired,
+                start_after,
+                limit,
+            }),
+            QueryMsg::NumTokens {} => Ok(Cw721QueryMsg::NumTokens {}),
+            QueryMsg::ContractInfo {} => Ok(Cw721QueryMsg::ContractInfo {}),
+            QueryMsg::NftInfo { token_id } => Ok(Cw721QueryMsg::NftInfo { token_id }),
             QueryMsg::AllNftInfo {
                 token_id,
                 include_expired,
-            } => Cw721QueryMsg::AllNftInfo {
+            } => Ok(Cw721QueryMsg::AllNftInfo {
                 token_id,
                 include_expired,
-            },
+            }),
             QueryMsg::Tokens {
                 owner,
                 start_after,
                 limit,
-            } => Cw721QueryMsg::Tokens {
+            } => Ok(Cw721QueryMsg::Tokens {
                 owner,
                 start_after,
                 limit,
-            },
+            }),
             QueryMsg::AllTokens { start_after, limit } => {
-                Cw721QueryMsg::AllTokens { start_after, limit }
+                Ok(Cw721QueryMsg::AllTokens { start_after, limit })
             }
-            QueryMsg::Minter {} => Cw721QueryMsg::Minter {},
-            _ => unreachable!("cannot convert {:?} to Cw721QueryMsg", msg),
+            QueryMsg::Minter {} => Ok(Cw721QueryMsg::Minter {}),
+            _ => Err(ContractError::UnsupportedMessage {}),
         }
     }
-}
\ No newline at end of file
+}
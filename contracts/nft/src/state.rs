@@ -0,0 +1,782 @@
+use cosmwasm_schema::cw_serde;
+use cosmwasm_std::{Addr, BlockInfo, Coin, Decimal, Empty, Order, OverflowError, OverflowOperation, StdError, StdResult, Storage, Timestamp, Uint128};
+use cw721::Expiration;
+use cw_storage_plus::{Item, Map, SnapshotItem, SnapshotMap, Strategy};
+
+use crate::contract::{Cw20, Metadata};
+use crate::error::ContractError;
+
+#[cw_serde]
+#[derive(Default)]
+pub struct Config {
+    /// Who may top up the escrow of an existing token
+    pub deposit_policy: DepositPolicy,
+    /// What the minter may change through `UpdateMetadata`
+    pub metadata_policy: MetadataPolicy,
+    /// Native denom whose escrowed amount is the staked principal of a token.
+    /// Tokens only earn rewards when it is set
+    pub staking_denom: Option<String>,
+    /// Native denoms and cw20 contract addresses that can be paid out as rewards
+    pub reward_denoms: Vec<String>,
+    /// Cw20 contracts whose tokens can be deposited into a token
+    pub accepted_cw20s: Vec<String>,
+    /// Validators the staking denom is delegated to, in equal parts
+    pub validators: Vec<String>,
+    /// Time in seconds undelegated coins take to come back to the contract
+    pub unbonding_period: u64,
+    /// Whether tokens waiting for their principal to unbond can change hands
+    pub unbonding_transfers: TransferPolicy,
+    /// Whether tokens still in their lock-up can change hands
+    pub locked_transfers: TransferPolicy,
+    /// Terms for leaving a lock-up early with `ExitEarly`, not possible if unset
+    pub early_exit: Option<EarlyExit>,
+    /// Voting power an escrowed unit of each asset is worth. Assets not listed
+    /// count for nothing
+    pub voting_weights: Vec<VotingWeight>,
+    /// Whether `Compound` adds the delegator rewards of a token to its escrow, unless
+    /// the token chose otherwise. Rewards not compounded are left to claim
+    pub auto_compound: bool,
+}
+
+impl Config {
+    /// Amount of the staking denom escrowed in `escrow`
+    pub fn principal(&self, escrow: &Metadata) -> Uint128 {
+        match &self.staking_denom {
+            Some(denom) => escrow
+                .native_balance()
+                .into_iter()
+                .find(|coin| &coin.denom == denom)
+                .map(|coin| coin.amount)
+                .unwrap_or_default(),
+            None => Uint128::zero(),
+        }
+    }
+
+    /// Time in seconds between two unbonding batches. At most seven batches are then
+    /// unbonding at once, the entries the chain keeps per validator by default
+    pub fn unbonding_epoch(&self) -> u64 {
+        self.unbonding_period.div_ceil(6)
+    }
+
+    /// Voting power of a token holding `escrow`
+    pub fn voting_power(&self, escrow: &Metadata) -> StdResult<Uint128> {
+        let native = escrow.native_balance().into_iter().map(|coin| (coin.denom, coin.amount));
+        let cw20 = escrow.cw20_balance().into_iter().map(|cw20| (cw20.contract_address, cw20.amount));
+        let mut power = Uint128::zero();
+        for (asset, amount) in native.chain(cw20) {
+            if let Some(weight) = self.voting_weights.iter().find(|weight| weight.asset == asset) {
+                let weighted = amount
+                    .checked_multiply_ratio(weight.weight.atomics(), Decimal::one().atomics())
+                    .map_err(|_| OverflowError::new(OverflowOperation::Mul, amount, weight.weight))?;
+                power = power.checked_add(weighted)?;
+            }
+        }
+        Ok(power)
+    }
+}
+
+/// Principal staked across all tokens
+pub fn total_principal(storage: &dyn Storage, config: &Config) -> StdResult<Uint128> {
+    match &config.staking_denom {
+        Some(denom) => Ok(TOTAL_NATIVE.may_load(storage, denom)?.unwrap_or_default()),
+        None => Ok(Uint128::zero()),
+    }
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub enum DepositPolicy {
+    /// Any account can deposit into any token
+    #[default]
+    Anyone,
+    /// Only the owner, or an account allowed to send the token, can deposit
+    OwnerOrApproved,
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub enum TransferPolicy {
+    /// The token cannot be transferred or sent
+    #[default]
+    Locked,
+    /// The token moves as usual
+    Allowed,
+}
+
+#[cw_serde]
+pub struct VotingWeight {
+    /// Native denom or cw20 contract address
+    pub asset: String,
+    pub weight: Decimal,
+}
+
+#[cw_serde]
+pub struct EarlyExit {
+    /// Share of every escrowed asset forfeited, at most one
+    pub penalty: Decimal,
+    /// Where the forfeited assets go
+    pub sink: PenaltySink,
+}
+
+#[cw_serde]
+pub enum PenaltySink {
+    /// Sent to the given address
+    Treasury { address: String },
+    /// Distributed to the remaining tokens pro-rata to their staked principal
+    Stakers,
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub enum MetadataPolicy {
+    /// The minter can rewrite the whole extension, balances included
+    #[default]
+    Unrestricted,
+    /// Only non-financial fields can be updated, balances change through deposits
+    /// and withdrawals alone. Once set, this cannot be reverted
+    NonFinancial,
+}
+
+pub const CONFIG: Item<Config> = Item::new("angel_config");
+
+/// Native coins escrowed across all tokens, by denom
+pub const TOTAL_NATIVE: Map<&str, Uint128> = Map::new("angel_total_native");
+/// Cw20 tokens escrowed across all tokens, by cw20 contract address
+pub const TOTAL_CW20: Map<&str, Uint128> = Map::new("angel_total_cw20");
+
+/// Staking denom delegated by the contract, by validator address
+pub const VALIDATOR_DELEGATIONS: Map<&str, Uint128> = Map::new("angel_validator_delegations");
+/// Delegation state of the tokens that delegated part of their principal
+pub const TOKEN_STAKES: Map<&str, TokenStake> = Map::new("angel_token_stakes");
+/// Every undelegation still unbonding, whatever token it belongs to
+pub const UNBONDING: Item<Vec<Unbonding>> = Item::new("angel_unbonding");
+/// Undelegations queued since the last batch was sent
+pub const UNBONDING_BATCH: Item<UnbondingBatch> = Item::new("angel_unbonding_batch");
+/// Tokens with principal queued in the next unbonding batch
+pub const BATCH_TOKENS: Map<&str, ()> = Map::new("angel_batch_tokens");
+/// Assets withdrawn from vesting tokens, they count towards what vested
+pub const VESTING_WITHDRAWN: Map<&str, Metadata> = Map::new("angel_vesting_withdrawn");
+/// Tokens overriding the collection-wide `auto_compound` setting
+pub const AUTO_COMPOUND: Map<&str, bool> = Map::new("angel_auto_compound");
+/// What is left of the principal bonded to the validators after the slashes so far
+pub const SLASH_INDEX: Item<SlashIndex> = Item::new("angel_slash_index");
+/// Slash index each token last applied to its bonded principal
+pub const SLASH_CHECKPOINTS: Map<&str, SlashIndex> = Map::new("angel_slash_checkpoints");
+
+/// Whether `Compound` adds the delegator rewards of `token_id` to its escrow
+pub fn auto_compounds(storage: &dyn Storage, config: &Config, token_id: &str) -> StdResult<bool> {
+    Ok(AUTO_COMPOUND.may_load(storage, token_id)?.unwrap_or(config.auto_compound))
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub struct TokenStake {
+    /// Principal of the token delegated to the validator set
+    pub delegated: Uint128,
+    /// Principal undelegated and not back in the contract balance yet
+    pub unbonding: Vec<Unbonding>,
+    /// Set once the token was redeemed while its principal was locked, it is burned
+    /// and paid out by `ClaimUnbonded` when the principal is back
+    pub redemption: Option<Redemption>,
+}
+
+impl TokenStake {
+    /// Principal that cannot leave the token, because it is delegated or still unbonding
+    pub fn locked(&self) -> Uint128 {
+        self.unbonding.iter().fold(self.delegated, |locked, unbonding| locked + unbonding.amount)
+    }
+
+    /// Principal still bonded to the validators, delegated or waiting for the next
+    /// unbonding batch
+    pub fn bonded(&self) -> Uint128 {
+        self.unbonding
+            .iter()
+            .filter(|unbonding| unbonding.queued())
+            .fold(self.delegated, |bonded, unbonding| bonded + unbonding.amount)
+    }
+
+    /// Applies the slashes between the `from` and `to` slash indices to the bonded
+    /// principal, and returns how much of it was lost
+    pub fn slash(&mut self, from: &SlashIndex, to: &SlashIndex) -> Uint128 {
+        let bonded = self.bonded();
+        self.delegated = from.slashed(self.delegated, to);
+        for unbonding in self.unbonding.iter_mut().filter(|unbonding| unbonding.queued()) {
+            unbonding.amount = from.slashed(unbonding.amount, to);
+        }
+        self.unbonding.retain(|unbonding| !unbonding.amount.is_zero());
+        bonded - self.bonded()
+    }
+
+    /// When the last unbonding of the stake is over, `now` if there is none
+    pub fn release_at(&self, now: Timestamp) -> Expiration {
+        self.unbonding
+            .iter()
+            .map(|unbonding| unbonding.release_at)
+            .fold(Expiration::AtTime(now), |latest, release_at| if release_at > latest { release_at } else { latest })
+    }
+
+    /// Adds the delegations and unbondings of `other` to this stake
+    pub fn merge(&mut self, other: &TokenStake) {
+        self.delegated += other.delegated;
+        self.unbonding.extend(other.unbonding.iter().cloned());
+    }
+}
+
+#[cw_serde]
+pub struct Unbonding {
+    pub amount: Uint128,
+    /// `Never` while the undelegation waits for the next unbonding batch
+    pub release_at: Expiration,
+}
+
+impl Unbonding {
+    /// Whether the undelegation waits for the next unbonding batch
+    pub fn queued(&self) -> bool {
+        matches!(self.release_at, Expiration::Never {})
+    }
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub struct UnbondingBatch {
+    /// Principal to undelegate with the next batch
+    pub amount: Uint128,
+    /// Time in seconds the last batch was sent
+    pub last_sent: u64,
+}
+
+#[cw_serde]
+pub struct SlashIndex {
+    /// Bumped by a slash of everything bonded, nothing is left of what was bonded in an
+    /// earlier era
+    pub era: u64,
+    /// What is left of a unit of principal bonded at the start of the era
+    pub ratio: Decimal,
+}
+
+impl Default for SlashIndex {
+    fn default() -> Self {
+        SlashIndex { era: 0, ratio: Decimal::one() }
+    }
+}
+
+impl SlashIndex {
+    /// Lowers the index by a slash of `loss` out of the `total_bonded` principal
+    pub fn slash(&mut self, loss: Uint128, total_bonded: Uint128) {
+        let ratio = if loss < total_bonded {
+            self.ratio * Decimal::from_ratio(total_bonded - loss, total_bonded)
+        } else {
+            Decimal::zero()
+        };
+        if ratio.is_zero() {
+            *self = SlashIndex { era: self.era + 1, ratio: Decimal::one() };
+        } else {
+            self.ratio = ratio;
+        }
+    }
+
+    /// What is left at the `now` index of `amount` bonded at this one
+    pub fn slashed(&self, amount: Uint128, now: &SlashIndex) -> Uint128 {
+        if self.era < now.era {
+            Uint128::zero()
+        } else {
+            amount.multiply_ratio(now.ratio.atomics(), self.ratio.atomics())
+        }
+    }
+}
+
+/// Current slash index of the collection
+pub fn slash_index(storage: &dyn Storage) -> StdResult<SlashIndex> {
+    Ok(SLASH_INDEX.may_load(storage)?.unwrap_or_default())
+}
+
+/// Slash index `token_id` last applied, the current one if it never bonded anything
+pub fn slash_checkpoint(storage: &dyn Storage, token_id: &str) -> StdResult<SlashIndex> {
+    match SLASH_CHECKPOINTS.may_load(storage, token_id)? {
+        Some(checkpoint) => Ok(checkpoint),
+        None => slash_index(storage),
+    }
+}
+
+#[cw_serde]
+pub struct Redemption {
+    /// Who gets the escrow, the owner at claim time if unset
+    pub recipient: Option<Addr>,
+    /// When the last unbonding of the token is over
+    pub release_at: Expiration,
+}
+
+/// Delegation state of `token_id`, without the unbondings that are over
+pub fn load_stake(storage: &dyn Storage, token_id: &str, block: &BlockInfo) -> StdResult<TokenStake> {
+    let mut stake = TOKEN_STAKES.may_load(storage, token_id)?.unwrap_or_default();
+    stake.unbonding.retain(|unbonding| !unbonding.release_at.is_expired(block));
+    Ok(stake)
+}
+
+/// Saves the delegation state of `token_id`, dropping it once nothing is locked
+/// and no redemption is pending
+pub fn save_stake(storage: &mut dyn Storage, token_id: &str, stake: &TokenStake) -> StdResult<()> {
+    if stake.locked().is_zero() && stake.redemption.is_none() {
+        TOKEN_STAKES.remove(storage, token_id);
+        Ok(())
+    } else {
+        TOKEN_STAKES.save(storage, token_id, stake)
+    }
+}
+
+/// Staking denom undelegated by the contract and not back in its balance yet
+pub fn unbonding_total(storage: &dyn Storage, block: &BlockInfo) -> StdResult<Uint128> {
+    Ok(UNBONDING
+        .may_load(storage)?
+        .unwrap_or_default()
+        .iter()
+        .filter(|unbonding| !unbonding.release_at.is_expired(block))
+        .fold(Uint128::zero(), |total, unbonding| total + unbonding.amount))
+}
+
+/// Records an undelegation of the contract, forgetting the ones that are over
+pub fn push_unbonding(storage: &mut dyn Storage, unbonding: Unbonding, block: &BlockInfo) -> StdResult<()> {
+    let mut queue = UNBONDING.may_load(storage)?.unwrap_or_default();
+    queue.retain(|unbonding| !unbonding.release_at.is_expired(block));
+    queue.push(unbonding);
+    UNBONDING.save(storage, &queue)
+}
+
+/// Voting power of every owner, weighted by what their tokens escrow
+pub const VOTING_POWER: SnapshotMap<&Addr, Uint128> = SnapshotMap::new(
+    "angel_voting_power",
+    "angel_voting_power__checkpoints",
+    "angel_voting_power__changelog",
+    Strategy::EveryBlock,
+);
+/// Voting power of the whole collection
+pub const TOTAL_POWER: SnapshotItem<Uint128> = SnapshotItem::new(
+    "angel_total_power",
+    "angel_total_power__checkpoints",
+    "angel_total_power__changelog",
+    Strategy::EveryBlock,
+);
+
+/// Owner of every token, by token id
+pub const TOKEN_OWNERS: SnapshotMap<&str, Addr> = SnapshotMap::new(
+    "angel_token_owners",
+    "angel_token_owners__checkpoints",
+    "angel_token_owners__changelog",
+    Strategy::EveryBlock,
+);
+/// Tokens of every owner, by owner and token id. Holds `Empty` rather than `()`, as
+/// the changelog can't tell a unit value from a missing one
+pub const OWNER_TOKENS: SnapshotMap<(&Addr, &str), Empty> = SnapshotMap::new(
+    "angel_owner_tokens",
+    "angel_owner_tokens__checkpoints",
+    "angel_owner_tokens__changelog",
+    Strategy::EveryBlock,
+);
+/// Escrow of every token, by token id
+pub const TOKEN_BALANCES: SnapshotMap<&str, Metadata> = SnapshotMap::new(
+    "angel_token_balances",
+    "angel_token_balances__checkpoints",
+    "angel_token_balances__changelog",
+    Strategy::EveryBlock,
+);
+
+/// Records the owner and escrow `token_id` has from `height` on, `None` once it is gone
+pub fn snapshot_token(
+    storage: &mut dyn Storage,
+    height: u64,
+    token_id: &str,
+    token: Option<(&Addr, &Metadata)>,
+) -> StdResult<()> {
+    let previous = TOKEN_OWNERS.may_load(storage, token_id)?;
+    let owner = token.map(|(owner, _)| owner);
+    if previous.as_ref() != owner {
+        if let Some(previous) = &previous {
+            OWNER_TOKENS.remove(storage, (previous, token_id), height)?;
+        }
+        if let Some(owner) = owner {
+            OWNER_TOKENS.save(storage, (owner, token_id), &Empty {}, height)?;
+        }
+    }
+
+    match token {
+        Some((owner, escrow)) => {
+            if previous.as_ref() != Some(owner) {
+                TOKEN_OWNERS.save(storage, token_id, owner, height)?;
+            }
+            TOKEN_BALANCES.save(storage, token_id, escrow, height)
+        }
+        None => {
+            TOKEN_OWNERS.remove(storage, token_id, height)?;
+            TOKEN_BALANCES.remove(storage, token_id, height)
+        }
+    }
+}
+
+/// Changes the voting power of `owner`, and the total, from what a token holding
+/// `old` is worth to what it is worth holding `new`
+pub fn update_power(
+    storage: &mut dyn Storage,
+    height: u64,
+    config: &Config,
+    owner: &Addr,
+    old: &Metadata,
+    new: &Metadata,
+) -> StdResult<()> {
+    let (old, new) = (config.voting_power(old)?, config.voting_power(new)?);
+    if old == new {
+        return Ok(());
+    }
+    let power = VOTING_POWER.may_load(storage, owner)?.unwrap_or_default();
+    VOTING_POWER.save(storage, owner, &power.checked_add(new)?.checked_sub(old)?, height)?;
+    let total = TOTAL_POWER.may_load(storage)?.unwrap_or_default();
+    TOTAL_POWER.save(storage, &total.checked_add(new)?.checked_sub(old)?, height)
+}
+
+/// Reward pools by asset, a native denom or a cw20 contract address
+pub const REWARD_POOLS: Map<&str, RewardPool> = Map::new("angel_reward_pools");
+/// Reward state of a token at its last checkpoint, by token id and reward asset
+pub const REWARD_CHECKPOINTS: Map<(&str, &str), RewardCheckpoint> = Map::new("angel_reward_checkpoints");
+
+#[cw_serde]
+#[derive(Default)]
+pub struct RewardPool {
+    /// Whether the asset is a cw20 contract address rather than a native denom
+    pub cw20: bool,
+    /// Rewards paid per unit of principal since the pool was first funded
+    pub index: Decimal,
+    /// Released to the index and not claimed yet
+    pub owed: Uint128,
+    /// Funded and not released to the index yet
+    pub streaming: Uint128,
+    /// Time in seconds the stream was last released up to
+    pub last_release: u64,
+    /// Time in seconds by which the whole stream is released
+    pub stream_end: u64,
+}
+
+impl RewardPool {
+    /// Moves the part of the stream due by `now` into the index. The stream is paused
+    /// while no principal is staked and resumes where it left off once there is some
+    fn release(&mut self, now: u64, total_principal: Uint128) -> StdResult<()> {
+        if total_principal.is_zero() {
+            if now > self.last_release && self.stream_end > self.last_release {
+                self.stream_end = self.stream_end.saturating_add(now - self.last_release);
+            }
+            self.last_release = self.last_release.max(now);
+            return Ok(());
+        }
+        let released = if now >= self.stream_end {
+            self.streaming
+        } else if now > self.last_release {
+            self.streaming.multiply_ratio(now - self.last_release, self.stream_end - self.last_release)
+        } else {
+            Uint128::zero()
+        };
+        self.index = self.index.checked_add(Decimal::from_ratio(released, total_principal))?;
+        self.owed = self.owed.checked_add(released)?;
+        self.streaming -= released;
+        self.last_release = self.last_release.max(now);
+        Ok(())
+    }
+}
+
+#[cw_serde]
+#[derive(Default)]
+pub struct RewardCheckpoint {
+    /// Value of the pool index when the token was last checkpointed
+    pub index: Decimal,
+    /// Rewards accrued up to that point and not claimed yet
+    pub pending: Uint128,
+}
+
+fn reward_pools(storage: &dyn Storage) -> StdResult<Vec<(String, RewardPool)>> {
+    REWARD_POOLS.range(storage, None, None, Order::Ascending).collect()
+}
+
+/// Reward pools with their streams released up to `now`, without saving them
+pub fn released_pools(storage: &dyn Storage, now: u64, total_principal: Uint128) -> StdResult<Vec<(String, RewardPool)>> {
+    let mut pools = reward_pools(storage)?;
+    for (_, pool) in pools.iter_mut() {
+        pool.release(now, total_principal)?;
+    }
+    Ok(pools)
+}
+
+/// Releases every reward stream up to `now`. Has to run before any principal changes
+/// or rewards are checkpointed, so earlier principal earns what was due until then
+pub fn release_rewards(storage: &mut dyn Storage, now: u64, total_principal: Uint128) -> StdResult<()> {
+    for (asset, pool) in released_pools(storage, now, total_principal)? {
+        REWARD_POOLS.save(storage, &asset, &pool)?;
+    }
+    Ok(())
+}
+
+/// Adds `amount` of `asset` to its pool, streamed linearly over the next `duration`
+/// seconds, and returns when the stream ends. A running stream is never sped up nor
+/// slowed down: it ends no earlier than it would have, and no later than its current
+/// rate pays out what it holds. Rewards have to be released up to `now` first
+pub fn fund_rewards(
+    storage: &mut dyn Storage,
+    asset: &str,
+    cw20: bool,
+    amount: Uint128,
+    now: u64,
+    duration: u64,
+) -> Result<u64, ContractError> {
+    let mut pool = REWARD_POOLS.may_load(storage, asset)?.unwrap_or(RewardPool { cw20, ..RewardPool::default() });
+    if pool.cw20 != cw20 {
+        return Err(ContractError::InvalidRewardDenom { denom: asset.to_string() });
+    }
+    let mut end = now.checked_add(duration).ok_or(ContractError::InvalidDuration { duration })?;
+    if !pool.streaming.is_zero() && pool.stream_end > now {
+        let left = pool.stream_end - now;
+        let streaming = pool.streaming.checked_add(amount).map_err(StdError::from)?;
+        let longest = Uint128::from(left)
+            .checked_multiply_ratio(streaming, pool.streaming)
+            .map(|longest| u64::try_from(longest.u128()).unwrap_or(u64::MAX))
+            .unwrap_or(u64::MAX);
+        end = end.clamp(pool.stream_end, now.saturating_add(longest));
+    }
+    pool.streaming = pool.streaming.checked_add(amount).map_err(StdError::from)?;
+    pool.last_release = now;
+    pool.stream_end = end;
+    REWARD_POOLS.save(storage, asset, &pool)?;
+    Ok(end)
+}
+
+/// Rewards distributed or streaming and not claimed yet, which the contract has to hold,
+/// delegator rewards harvested and not compounded or credited included
+pub fn reward_liabilities(storage: &dyn Storage) -> StdResult<Metadata> {
+    let mut liabilities = Metadata::default();
+    for item in REWARD_POOLS.range(storage, None, None, Order::Ascending) {
+        let (asset, pool) = item?;
+        liabilities.merge(&pool_metadata(&asset, &pool, pool.owed + pool.streaming));
+    }
+    let mut harvested = vec![];
+    for item in DELEGATOR_REWARDS.range(storage, None, None, Order::Ascending) {
+        let (denom, amount) = item?;
+        harvested.push(Coin { denom, amount });
+    }
+    liabilities.merge(&Metadata { native: Some(harvested), cw20: None, locked_until: None, vesting: None });
+    Ok(liabilities)
+}
+
+/// Delegator rewards harvested per unit of principal bonded before any slash since the
+/// first delegation, by denom. Slashes leave what a token earns per unit alone
+pub const DELEGATOR_INDEX: Map<&str, Decimal> = Map::new("angel_delegator_index");
+/// Delegator reward state of a token when its bonded principal last changed, by token
+/// id and denom
+pub const DELEGATOR_CHECKPOINTS: Map<(&str, &str), RewardCheckpoint> = Map::new("angel_delegator_checkpoints");
+/// Delegator rewards harvested and not compounded or credited to a token yet, by denom
+pub const DELEGATOR_REWARDS: Map<&str, Uint128> = Map::new("angel_delegator_rewards");
+/// Block height delegator rewards were last harvested at
+pub const LAST_HARVEST: Item<u64> = Item::new("angel_last_harvest");
+
+/// Shares `rewards`, just harvested from the validators, over the `total_bonded` principal
+pub fn add_delegator_rewards(storage: &mut dyn Storage, rewards: &[Coin], total_bonded: Uint128) -> StdResult<()> {
+    if total_bonded.is_zero() {
+        return Ok(());
+    }
+    let ratio = slash_index(storage)?.ratio;
+    for coin in rewards {
+        let index = DELEGATOR_INDEX.may_load(storage, &coin.denom)?.unwrap_or_default();
+        let per_unit = Decimal::from_ratio(coin.amount, total_bonded).checked_mul(ratio)?;
+        DELEGATOR_INDEX.save(storage, &coin.denom, &index.checked_add(per_unit)?)?;
+        let harvested = DELEGATOR_REWARDS.may_load(storage, &coin.denom)?.unwrap_or_default();
+        DELEGATOR_REWARDS.save(storage, &coin.denom, &harvested.checked_add(coin.amount)?)?;
+    }
+    Ok(())
+}
+
+/// Accrues the delegator rewards of `token_id` up to the last harvest. Has to be called
+/// with the old bonded principal before the bonded principal of a token changes
+pub fn accrue_delegator_rewards(storage: &mut dyn Storage, token_id: &str, bonded: Uint128) -> StdResult<()> {
+    let ratio = slash_checkpoint(storage, token_id)?.ratio;
+    let indices = DELEGATOR_INDEX.range(storage, None, None, Order::Ascending).collect::<StdResult<Vec<_>>>()?;
+    for (denom, index) in indices {
+        let mut checkpoint = DELEGATOR_CHECKPOINTS.may_load(storage, (token_id, &denom))?.unwrap_or_default();
+        let accrued = bonded.multiply_ratio((index - checkpoint.index).atomics(), ratio.atomics());
+        checkpoint.pending = checkpoint.pending.checked_add(accrued)?;
+        checkpoint.index = index;
+        DELEGATOR_CHECKPOINTS.save(storage, (token_id, &denom), &checkpoint)?;
+    }
+    Ok(())
+}
+
+/// Delegator rewards `token_id` can take out, accrued up to the last harvest
+pub fn pending_delegator_rewards(storage: &dyn Storage, token_id: &str, bonded: Uint128) -> StdResult<Vec<Coin>> {
+    let ratio = slash_checkpoint(storage, token_id)?.ratio;
+    let mut rewards = vec![];
+    for item in DELEGATOR_INDEX.range(storage, None, None, Order::Ascending) {
+        let (denom, index) = item?;
+        let checkpoint = DELEGATOR_CHECKPOINTS.may_load(storage, (token_id, &denom))?.unwrap_or_default();
+        let accrued = bonded.multiply_ratio((index - checkpoint.index).atomics(), ratio.atomics());
+        let amount = checkpoint.pending.checked_add(accrued)?;
+        if !amount.is_zero() {
+            rewards.push(Coin { denom, amount });
+        }
+    }
+    Ok(rewards)
+}
+
+/// Accrues and takes out the delegator rewards of `token_id`, which start accruing
+/// again from now
+pub fn take_delegator_rewards(storage: &mut dyn Storage, token_id: &str, bonded: Uint128) -> StdResult<Vec<Coin>> {
+    accrue_delegator_rewards(storage, token_id, bonded)?;
+    let checkpoints = DELEGATOR_CHECKPOINTS
+        .prefix(token_id)
+        .range(storage, None, None, Order::Ascending)
+        .collect::<StdResult<Vec<_>>>()?;
+    let mut rewards = vec![];
+    for (denom, checkpoint) in checkpoints {
+        if checkpoint.pending.is_zero() {
+            continue;
+        }
+        let harvested = DELEGATOR_REWARDS.load(storage, &denom)?;
+        DELEGATOR_REWARDS.save(storage, &denom, &harvested.checked_sub(checkpoint.pending)?)?;
+        DELEGATOR_CHECKPOINTS.save(storage, (token_id, &denom), &RewardCheckpoint { index: checkpoint.index, pending: Uint128::zero() })?;
+        rewards.push(Coin { denom, amount: checkpoint.pending });
+    }
+    Ok(rewards)
+}
+
+/// Takes out the delegator rewards of a token leaving the contract and drops its
+/// checkpoints, its slash checkpoint included
+pub fn close_delegator_rewards(storage: &mut dyn Storage, token_id: &str, bonded: Uint128) -> StdResult<Vec<Coin>> {
+    let rewards = take_delegator_rewards(storage, token_id, bonded)?;
+    let denoms = DELEGATOR_CHECKPOINTS.prefix(token_id).keys(storage, None, None, Order::Ascending).collect::<StdResult<Vec<_>>>()?;
+    for denom in denoms {
+        DELEGATOR_CHECKPOINTS.remove(storage, (token_id, &denom));
+    }
+    SLASH_CHECKPOINTS.remove(storage, token_id);
+    Ok(rewards)
+}
+
+/// `amount` of the asset of `pool` as escrow-like assets
+fn pool_metadata(asset: &str, pool: &RewardPool, amount: Uint128) -> Metadata {
+    if pool.cw20 {
+        Metadata { native: None, cw20: Some(vec![Cw20 { contract_address: asset.to_string(), amount }]), locked_until: None, vesting: None }
+    } else {
+        Metadata { native: Some(vec![Coin { denom: asset.to_string(), amount }]), cw20: None, locked_until: None, vesting: None }
+    }
+}
+
+/// Reward state of `token_id` in every pool of `pools`, accrued up to their index.
+/// Must be computed with the principal the token held since its last checkpoint
+fn accrued_rewards(
+    storage: &dyn Storage,
+    pools: &[(String, RewardPool)],
+    token_id: &str,
+    principal: Uint128,
+) -> StdResult<Vec<RewardCheckpoint>> {
+    pools
+        .iter()
+        .map(|(asset, pool)| {
+            let checkpoint = REWARD_CHECKPOINTS.may_load(storage, (token_id, asset))?.unwrap_or_default();
+            let pending = checkpoint.pending.checked_add(principal * (pool.index - checkpoint.index))?;
+            Ok(RewardCheckpoint { index: pool.index, pending })
+        })
+        .collect()
+}
+
+/// Rewards `token_id` could claim from `pools`, given the principal it held since its
+/// last checkpoint
+pub fn pending_rewards(
+    storage: &dyn Storage,
+    pools: &[(String, RewardPool)],
+    token_id: &str,
+    principal: Uint128,
+) -> StdResult<Metadata> {
+    let mut rewards = Metadata::default();
+    for ((asset, pool), checkpoint) in pools.iter().zip(accrued_rewards(storage, pools, token_id, principal)?) {
+        rewards.merge(&pool_metadata(asset, pool, checkpoint.pending));
+    }
+    Ok(rewards)
+}
+
+/// Accrues the rewards of `token_id` up to now. Has to be called with the old principal
+/// before the principal of a token changes, and with zero before a token is created
+pub fn checkpoint_rewards(storage: &mut dyn Storage, token_id: &str, principal: Uint128) -> StdResult<()> {
+    let pools = reward_pools(storage)?;
+    for ((asset, _), checkpoint) in pools.iter().zip(accrued_rewards(storage, &pools, token_id, principal)?) {
+        REWARD_CHECKPOINTS.save(storage, (token_id, asset), &checkpoint)?;
+    }
+    Ok(())
+}
+
+/// Accrues and takes out every pending reward of `token_id`, returning what has to be paid
+pub fn claim_rewards(storage: &mut dyn Storage, token_id: &str, principal: Uint128) -> StdResult<Metadata> {
+    let pools = reward_pools(storage)?;
+    let accrued = accrued_rewards(storage, &pools, token_id, principal)?;
+    let mut rewards = Metadata::default();
+    for ((asset, mut pool), checkpoint) in pools.into_iter().zip(accrued) {
+        rewards.merge(&pool_metadata(&asset, &pool, checkpoint.pending));
+        pool.owed = pool.owed.checked_sub(checkpoint.pending)?;
+        REWARD_POOLS.save(storage, &asset, &pool)?;
+        let checkpoint = RewardCheckpoint { index: checkpoint.index, pending: Uint128::zero() };
+        REWARD_CHECKPOINTS.save(storage, (token_id, &asset), &checkpoint)?;
+    }
+    Ok(rewards)
+}
+
+/// Owes `amount` of the native `denom` to `token_id` alone, on top of what it earns
+/// from the pools. The token has to be checkpointed first
+pub fn credit_rewards(storage: &mut dyn Storage, token_id: &str, denom: &str, amount: Uint128) -> Result<(), ContractError> {
+    let mut pool = REWARD_POOLS.may_load(storage, denom)?.unwrap_or_default();
+    if pool.cw20 {
+        return Err(ContractError::InvalidRewardDenom { denom: denom.to_string() });
+    }
+    let mut checkpoint = REWARD_CHECKPOINTS
+        .may_load(storage, (token_id, denom))?
+        .unwrap_or(RewardCheckpoint { index: pool.index, pending: Uint128::zero() });
+    checkpoint.pending = checkpoint.pending.checked_add(amount).map_err(StdError::from)?;
+    pool.owed = pool.owed.checked_add(amount).map_err(StdError::from)?;
+    REWARD_CHECKPOINTS.save(storage, (token_id, denom), &checkpoint)?;
+    REWARD_POOLS.save(storage, denom, &pool)?;
+    Ok(())
+}
+
+/// Claims the rewards of a token that is going away and drops its checkpoints
+pub fn close_rewards(storage: &mut dyn Storage, token_id: &str, principal: Uint128) -> StdResult<Metadata> {
+    let rewards = claim_rewards(storage, token_id, principal)?;
+    for (asset, _) in reward_pools(storage)? {
+        REWARD_CHECKPOINTS.remove(storage, (token_id, &asset));
+    }
+    Ok(rewards)
+}
+
+/// Adds the assets in `escrow` to the collection totals
+pub fn add_to_totals(storage: &mut dyn Storage, escrow: &Metadata) -> StdResult<()> {
+    for coin in escrow.native_balance() {
+        TOTAL_NATIVE.update(storage, &coin.denom, |total| -> StdResult<_> {
+            Ok(total.unwrap_or_default().checked_add(coin.amount)?)
+        })?;
+    }
+    for cw20 in escrow.cw20_balance() {
+        TOTAL_CW20.update(storage, &cw20.contract_address, |total| -> StdResult<_> {
+            Ok(total.unwrap_or_default().checked_add(cw20.amount)?)
+        })?;
+    }
+    Ok(())
+}
+
+/// Removes the assets in `escrow` from the collection totals, dropping emptied entries
+pub fn sub_from_totals(storage: &mut dyn Storage, escrow: &Metadata) -> StdResult<()> {
+    for coin in escrow.native_balance() {
+        let total = TOTAL_NATIVE.may_load(storage, &coin.denom)?.unwrap_or_default().checked_sub(coin.amount)?;
+        if total.is_zero() {
+            TOTAL_NATIVE.remove(storage, &coin.denom);
+        } else {
+            TOTAL_NATIVE.save(storage, &coin.denom, &total)?;
+        }
+    }
+    for cw20 in escrow.cw20_balance() {
+        let total = TOTAL_CW20.may_load(storage, &cw20.contract_address)?.unwrap_or_default().checked_sub(cw20.amount)?;
+        if total.is_zero() {
+            TOTAL_CW20.remove(storage, &cw20.contract_address);
+        } else {
+            TOTAL_CW20.save(storage, &cw20.contract_address, &total)?;
+        }
+    }
+    Ok(())
+}